@@ -0,0 +1,103 @@
+//! Compares how long it takes to load a chunk of state groups from the
+//! database using a normal query and using a binary COPY.
+//!
+//! This needs the testing database to be running (see the README) and fills
+//! it with a synthetic room, so it empties the database first. Run it with
+//!
+//! ```text
+//! cargo bench -p compressor_integration_tests
+//! ```
+//!
+//! The size of the room can be changed with the `BENCH_STATE_GROUPS` and
+//! `BENCH_ROWS_PER_GROUP` environment variables.
+
+use std::{env, time::Instant};
+
+use compressor_integration_tests::{empty_database, DB_URL};
+use synapse_compress_state::{connect_to_database, CopyLoader, StateGroupStore, TlsConfig};
+
+const ROOM_ID: &str = "!bench:test";
+
+/// How many times each loader is run (the fastest run is reported)
+const RUNS: usize = 3;
+
+fn env_or(name: &str, default: i64) -> i64 {
+    env::var(name)
+        .ok()
+        .map(|v| v.parse().expect("must be an integer"))
+        .unwrap_or(default)
+}
+
+fn main() {
+    let state_groups = env_or("BENCH_STATE_GROUPS", 20_000);
+    let rows_per_group = env_or("BENCH_ROWS_PER_GROUP", 50);
+
+    empty_database();
+
+    let mut client = connect_to_database(DB_URL, &TlsConfig::default()).unwrap();
+
+    // Chains of 100 groups, where each group has rows_per_group deltas
+    println!(
+        "Creating {} state groups with {} rows each...",
+        state_groups, rows_per_group
+    );
+    client
+        .batch_execute(&format!(
+            r#"
+            INSERT INTO state_groups (id, room_id, event_id)
+                SELECT g, '{room}', 'left_blank' FROM generate_series(0, {groups} - 1) AS g;
+            INSERT INTO state_group_edges (state_group, prev_state_group)
+                SELECT g, g - 1 FROM generate_series(1, {groups} - 1) AS g WHERE g % 100 != 0;
+            INSERT INTO state_groups_state (state_group, room_id, type, state_key, event_id)
+                SELECT g, '{room}', 'm.room.member', '@user' || k || ':test', '$' || g || '_' || k
+                FROM generate_series(0, {groups} - 1) AS g, generate_series(0, {rows} - 1) AS k;
+            ANALYZE;
+            "#,
+            room = ROOM_ID,
+            groups = state_groups,
+            rows = rows_per_group
+        ))
+        .unwrap();
+
+    let max_group = state_groups - 1;
+
+    let mut query_best = None;
+    let mut copy_best = None;
+
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        let query_map = client.get_initial_data(ROOM_ID, None, max_group).unwrap();
+        let query_time = start.elapsed();
+
+        let start = Instant::now();
+        let copy_map = CopyLoader(&mut client)
+            .get_initial_data(ROOM_ID, None, max_group)
+            .unwrap();
+        let copy_time = start.elapsed();
+
+        assert_eq!(query_map, copy_map);
+
+        query_best = Some(query_best.map_or(query_time, |best| query_time.min(best)));
+        copy_best = Some(copy_best.map_or(copy_time, |best| copy_time.min(best)));
+    }
+
+    let (query_best, copy_best) = (query_best.unwrap(), copy_best.unwrap());
+    let rows = state_groups * rows_per_group;
+
+    println!(
+        "query: {:>10.3?} ({:.0} rows/s)",
+        query_best,
+        rows as f64 / query_best.as_secs_f64()
+    );
+    println!(
+        "copy:  {:>10.3?} ({:.0} rows/s)",
+        copy_best,
+        rows as f64 / copy_best.as_secs_f64()
+    );
+    println!(
+        "copy took {:.1}% of the time of query",
+        100.0 * copy_best.as_secs_f64() / query_best.as_secs_f64()
+    );
+
+    empty_database();
+}
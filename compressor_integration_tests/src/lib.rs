@@ -314,6 +314,7 @@ pub fn clear_compressor_state() {
     let sql = r"
         TRUNCATE state_compressor_state;
         TRUNCATE state_compressor_progress;
+        TRUNCATE state_compressor_history;
         UPDATE state_compressor_total_progress SET lowest_uncompressed_group = 0;
     ";
 
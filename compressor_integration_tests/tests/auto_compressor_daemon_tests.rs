@@ -0,0 +1,268 @@
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use compressor_integration_tests::{
+    add_contents_to_database, clear_compressor_state, database_collapsed_states_match_map,
+    empty_database, map_builder::line_segments_with_state, setup_logger, DB_URL,
+};
+use serial_test::serial;
+use synapse_auto_compressor::{
+    daemon::{run_daemon, CronSchedule, Schedule},
+    metrics::METRICS,
+    room_filter::RoomFilter,
+    room_selection::RoomSelection,
+    state_saving::{connect_to_database, create_tables_if_needed, read_room_compressor_state},
+};
+use synapse_compress_state::{Level, TlsConfig};
+
+fn time(secs: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+// 2024-02-28 12:34:56 UTC, a Wednesday
+const WEDNESDAY_AFTERNOON: u64 = 1709123696;
+
+#[test]
+fn cron_schedule_finds_next_matching_minute() {
+    let cron: CronSchedule = "*/15 * * * *".parse().unwrap();
+    // 2024-02-28 12:45:00
+    assert_eq!(
+        cron.next_after(time(WEDNESDAY_AFTERNOON)),
+        Some(time(1709124300))
+    );
+    // A time that matches exactly moves on to the next one
+    assert_eq!(cron.next_after(time(1709124300)), Some(time(1709125200)));
+
+    // Odd days of the month: 2024-02-29 then 2024-03-01 and 2024-03-03
+    let cron: CronSchedule = "0 0 */2 * *".parse().unwrap();
+    assert_eq!(
+        cron.next_after(time(WEDNESDAY_AFTERNOON)),
+        Some(time(1709164800))
+    );
+    assert_eq!(cron.next_after(time(1709164800)), Some(time(1709251200)));
+    assert_eq!(cron.next_after(time(1709251200)), Some(time(1709424000)));
+
+    // The stepped day of the month still applies along with the day of the week:
+    // 2024-03-04 is a Monday but an even day, so 2024-03-11
+    let cron: CronSchedule = "0 0 */2 * 1".parse().unwrap();
+    assert_eq!(
+        cron.next_after(time(WEDNESDAY_AFTERNOON)),
+        Some(time(1710115200))
+    );
+
+    // 2024-02-29 03:00:00 (a leap day)
+    let cron: CronSchedule = "0 3 * * *".parse().unwrap();
+    assert_eq!(
+        cron.next_after(time(WEDNESDAY_AFTERNOON)),
+        Some(time(1709175600))
+    );
+
+    // 2024-03-01 00:00:00
+    let cron: CronSchedule = "@monthly".parse().unwrap();
+    assert_eq!(
+        cron.next_after(time(WEDNESDAY_AFTERNOON)),
+        Some(time(1709251200))
+    );
+
+    // 2024-03-04 00:00:00, the next Monday
+    let cron: CronSchedule = "0 0 * * 1".parse().unwrap();
+    assert_eq!(
+        cron.next_after(time(WEDNESDAY_AFTERNOON)),
+        Some(time(1709510400))
+    );
+
+    // 2028-02-29 00:00:00, the next leap day after 2024's
+    let cron: CronSchedule = "0 0 29 2 *".parse().unwrap();
+    assert_eq!(cron.next_after(time(1709251200)), Some(time(1835395200)));
+}
+
+#[test]
+fn cron_schedule_matches_either_day_field() {
+    // The 1st of the month or a Monday
+    let cron: CronSchedule = "0 0 1 * 1".parse().unwrap();
+
+    // 2024-03-01 00:00:00 is a Friday
+    assert_eq!(
+        cron.next_after(time(WEDNESDAY_AFTERNOON)),
+        Some(time(1709251200))
+    );
+    // 2024-03-04 00:00:00 is a Monday
+    assert_eq!(cron.next_after(time(1709251200)), Some(time(1709510400)));
+}
+
+#[test]
+fn cron_schedule_parses_sunday_as_0_or_7() {
+    assert_eq!(
+        "0 0 * * 7".parse::<CronSchedule>(),
+        "0 0 * * 0".parse::<CronSchedule>()
+    );
+    assert_eq!(
+        "@weekly".parse::<CronSchedule>(),
+        "0 0 * * 0".parse::<CronSchedule>()
+    );
+}
+
+#[test]
+fn cron_schedule_rejects_invalid_expressions() {
+    for expression in [
+        "60 * * * *",
+        "* * *",
+        "* * * * * *",
+        "*/0 * * * *",
+        "a * * * *",
+        "5-1 * * * *",
+        "0 0 31 2 *",
+    ] {
+        assert!(
+            expression.parse::<CronSchedule>().is_err(),
+            "{} should be rejected",
+            expression
+        );
+    }
+}
+
+#[test]
+fn schedule_backs_off_after_idle_passes() {
+    let start = time(WEDNESDAY_AFTERNOON);
+
+    let interval = Schedule::Interval(Duration::from_secs(60));
+    assert_eq!(
+        interval.next_run(start, 0),
+        Some(start + Duration::from_secs(60))
+    );
+    assert_eq!(
+        interval.next_run(start, 1),
+        Some(start + Duration::from_secs(120))
+    );
+    // The backoff stops growing after 4 idle passes
+    assert_eq!(
+        interval.next_run(start, 10),
+        Some(start + Duration::from_secs(16 * 60))
+    );
+
+    // 2024-02-28 16:00:00, skipping the passes at 13:00, 14:00 and 15:00
+    let hourly = Schedule::Cron("@hourly".parse().unwrap());
+    assert_eq!(hourly.next_run(start, 2), Some(time(1709136000)));
+}
+
+#[test]
+#[serial(db)]
+fn run_daemon_compresses_until_stopped() {
+    setup_logger();
+    // This creates 2 rooms with the following structure
+    //
+    // 0-1-2 3-4-5 6-7-8 9-10-11 12-13
+    // (with room2's numbers shifted up 14)
+    let initial1 = line_segments_with_state(0, 13);
+    let initial2 = line_segments_with_state(14, 27);
+
+    empty_database();
+    add_contents_to_database("room1", &initial1);
+    add_contents_to_database("room2", &initial2);
+
+    let mut clients = vec![connect_to_database(DB_URL, &TlsConfig::default()).unwrap()];
+    create_tables_if_needed(&mut clients[0]).unwrap();
+    clear_compressor_state();
+
+    let default_levels = vec![Level::new(3), Level::new(3)];
+    let stop = AtomicBool::new(false);
+
+    // Each pass only compresses one chunk, so it takes several passes to
+    // compress both rooms
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            thread::sleep(Duration::from_secs(3));
+            stop.store(true, Ordering::SeqCst);
+        });
+
+        run_daemon(
+            &mut clients,
+            DB_URL,
+            &TlsConfig::default(),
+            7,
+            &default_levels,
+            1,
+            RoomSelection::LowestId.selector(),
+            &RoomFilter::default(),
+            None,
+            &Schedule::Interval(Duration::from_millis(10)),
+            &stop,
+            false,
+            false,
+        )
+        .unwrap();
+    });
+
+    for (room_id, last_group) in [("room1", 13), ("room2", 27)] {
+        let (last_compressed, _) = read_room_compressor_state(&mut clients[0], room_id)
+            .unwrap()
+            .unwrap();
+        assert_eq!(last_compressed, last_group);
+    }
+    assert!(database_collapsed_states_match_map(&initial1));
+    assert!(database_collapsed_states_match_map(&initial2));
+}
+
+#[test]
+#[serial(db)]
+fn run_daemon_reconnects_after_a_failed_pass() {
+    setup_logger();
+    let initial = line_segments_with_state(0, 13);
+
+    empty_database();
+    add_contents_to_database("room1", &initial);
+
+    let mut clients = vec![connect_to_database(DB_URL, &TlsConfig::default()).unwrap()];
+    create_tables_if_needed(&mut clients[0]).unwrap();
+    clear_compressor_state();
+
+    // Kill the worker's connection (as if the database had restarted), so the
+    // first pass fails
+    let pid: i32 = clients[0]
+        .query_one("SELECT pg_backend_pid()", &[])
+        .unwrap()
+        .get(0);
+    let mut admin = connect_to_database(DB_URL, &TlsConfig::default()).unwrap();
+    admin
+        .execute("SELECT pg_terminate_backend($1)", &[&pid])
+        .unwrap();
+
+    let default_levels = vec![Level::new(3), Level::new(3)];
+    let stop = AtomicBool::new(false);
+
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            thread::sleep(Duration::from_secs(2));
+            stop.store(true, Ordering::SeqCst);
+        });
+
+        run_daemon(
+            &mut clients,
+            DB_URL,
+            &TlsConfig::default(),
+            100,
+            &default_levels,
+            1,
+            RoomSelection::LowestId.selector(),
+            &RoomFilter::default(),
+            None,
+            &Schedule::Interval(Duration::from_millis(10)),
+            &stop,
+            false,
+            false,
+        )
+        .unwrap();
+    });
+
+    assert!(METRICS
+        .render()
+        .contains("synapse_auto_compressor_daemon_failed_passes_total 1\n"));
+    let (last_compressed, _) = read_room_compressor_state(&mut clients[0], "room1")
+        .unwrap()
+        .unwrap();
+    assert_eq!(last_compressed, 13);
+    assert!(database_collapsed_states_match_map(&initial));
+}
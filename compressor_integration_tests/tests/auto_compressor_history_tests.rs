@@ -0,0 +1,187 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use compressor_integration_tests::{
+    add_contents_to_database, clear_compressor_state, empty_database,
+    map_builder::line_segments_with_state, setup_logger, DB_URL,
+};
+use postgres::Client;
+use serial_test::serial;
+use synapse_auto_compressor::{
+    format_timestamp,
+    manager::{compress_chunks_of_database, run_compressor_on_room_chunk},
+    room_filter::RoomFilter,
+    room_selection::RoomSelection,
+    state_saving::{
+        connect_to_database, create_tables_if_needed, read_chunk_history, read_history_summary,
+        write_chunk_history, ChunkHistory, HistorySummary,
+    },
+};
+use synapse_compress_state::{Level, TlsConfig};
+
+fn setup_rooms() -> Client {
+    setup_logger();
+    // This creates 2 rooms with the following structure
+    //
+    // 0-1-2 3-4-5 6-7-8 9-10-11 12-13
+    // (with room2's numbers shifted up 14)
+    empty_database();
+    add_contents_to_database("room1", &line_segments_with_state(0, 13));
+    add_contents_to_database("room2", &line_segments_with_state(14, 27));
+
+    let mut client = connect_to_database(DB_URL, &TlsConfig::default()).unwrap();
+    create_tables_if_needed(&mut client).unwrap();
+    clear_compressor_state();
+    client
+}
+
+fn now_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as i64
+}
+
+fn history_entry(room_id: &str, end_group: i64, committed: bool, ts: i64) -> ChunkHistory {
+    ChunkHistory {
+        room_id: room_id.to_string(),
+        start_group: None,
+        end_group,
+        level_sizes: "3,3".to_string(),
+        original_num_rows: 20,
+        new_num_rows: 5,
+        committed,
+        duration_ms: 100,
+        tool_version: "0.1.0".to_string(),
+        ts,
+    }
+}
+
+#[test]
+#[serial(db)]
+fn compressing_a_chunk_records_its_history() {
+    let mut client = setup_rooms();
+    let started = now_ms();
+    let default_levels = vec![Level::new(3), Level::new(3)];
+
+    for _ in 0..2 {
+        run_compressor_on_room_chunk(&mut client, "room1", 7, &default_levels, false, false)
+            .unwrap()
+            .unwrap();
+    }
+
+    let history = read_chunk_history(&mut client, Some("room1"), 10).unwrap();
+    assert_eq!(history.len(), 2);
+
+    // The most recent chunk comes first
+    let (second, first) = (&history[0], &history[1]);
+    assert_eq!(first.start_group, None);
+    assert_eq!(first.end_group, 6);
+    assert_eq!(second.start_group, Some(6));
+    assert_eq!(second.end_group, 13);
+
+    for chunk in &history {
+        assert_eq!(chunk.room_id, "room1");
+        assert_eq!(chunk.level_sizes, "3,3");
+        assert!(chunk.committed);
+        assert!(chunk.new_num_rows < chunk.original_num_rows);
+        assert!(chunk.duration_ms >= 0);
+        assert!(!chunk.tool_version.is_empty());
+        assert!(chunk.ts >= started && chunk.ts <= now_ms());
+    }
+}
+
+#[test]
+#[serial(db)]
+fn compressing_atomically_records_history() {
+    let mut client = setup_rooms();
+    let default_levels = vec![Level::new(3), Level::new(3)];
+
+    run_compressor_on_room_chunk(&mut client, "room2", 100, &default_levels, true, false)
+        .unwrap()
+        .unwrap();
+
+    let history = read_chunk_history(&mut client, None, 10).unwrap();
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0].room_id, "room2");
+    assert_eq!(history[0].end_group, 27);
+}
+
+#[test]
+#[serial(db)]
+fn compress_chunks_of_database_records_history_for_every_room() {
+    let mut client = setup_rooms();
+    let default_levels = vec![Level::new(3), Level::new(3)];
+
+    let run_stats = compress_chunks_of_database(
+        &mut client,
+        7,
+        &default_levels,
+        10,
+        RoomSelection::LowestId.selector(),
+        &RoomFilter::default(),
+        None,
+        false,
+        false,
+    )
+    .unwrap();
+
+    let history = read_chunk_history(&mut client, None, 10).unwrap();
+    assert_eq!(history.len() as i64, run_stats.chunks_processed);
+
+    let summary = read_history_summary(&mut client, None).unwrap();
+    assert_eq!(summary.rooms, 2);
+    assert_eq!(summary.committed_chunks + summary.skipped_chunks, 4);
+    assert_eq!(summary.rows_saved as usize, run_stats.rows_saved);
+
+    let summary = read_history_summary(&mut client, Some("room2")).unwrap();
+    assert_eq!(summary.rooms, 1);
+    assert_eq!(summary.committed_chunks + summary.skipped_chunks, 2);
+}
+
+#[test]
+#[serial(db)]
+fn read_chunk_history_filters_by_room_and_limit() {
+    let mut client = setup_rooms();
+
+    write_chunk_history(&mut client, &history_entry("room1", 6, true, 1000)).unwrap();
+    write_chunk_history(&mut client, &history_entry("room2", 20, false, 2000)).unwrap();
+    write_chunk_history(&mut client, &history_entry("room1", 13, true, 3000)).unwrap();
+
+    let history = read_chunk_history(&mut client, None, 10).unwrap();
+    assert_eq!(
+        history,
+        vec![
+            history_entry("room1", 13, true, 3000),
+            history_entry("room2", 20, false, 2000),
+            history_entry("room1", 6, true, 1000),
+        ]
+    );
+
+    let history = read_chunk_history(&mut client, Some("room1"), 1).unwrap();
+    assert_eq!(history, vec![history_entry("room1", 13, true, 3000)]);
+
+    // Only the committed chunks saved any rows
+    assert_eq!(
+        read_history_summary(&mut client, None).unwrap(),
+        HistorySummary {
+            rooms: 2,
+            committed_chunks: 2,
+            skipped_chunks: 1,
+            rows_saved: 30,
+            first_ts: Some(1000),
+            last_ts: Some(3000),
+        }
+    );
+    assert_eq!(
+        read_history_summary(&mut client, Some("room3")).unwrap(),
+        HistorySummary::default()
+    );
+}
+
+#[test]
+fn format_timestamp_gives_utc_time() {
+    assert_eq!(format_timestamp(0), "1970-01-01 00:00:00");
+    assert_eq!(format_timestamp(1709123696789), "2024-02-28 12:34:56");
+    assert_eq!(format_timestamp(951782400000), "2000-02-29 00:00:00");
+    assert_eq!(format_timestamp(1735689599000), "2024-12-31 23:59:59");
+}
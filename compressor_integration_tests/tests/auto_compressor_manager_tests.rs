@@ -1,4 +1,4 @@
-use std::collections::BTreeMap;
+use std::{collections::BTreeMap, sync::atomic::AtomicBool, time::Duration};
 
 use compressor_integration_tests::{
     add_contents_to_database, clear_compressor_state, database_collapsed_states_match_map,
@@ -11,10 +11,18 @@ use compressor_integration_tests::{
 };
 use serial_test::serial;
 use synapse_auto_compressor::{
-    manager::{compress_chunks_of_database, run_compressor_on_room_chunk},
-    state_saving::{connect_to_database, create_tables_if_needed},
+    manager::{
+        compress_chunks_of_database, compress_chunks_of_database_with_workers,
+        run_compressor_on_room_chunk,
+    },
+    room_filter::RoomFilter,
+    room_selection::RoomSelection,
+    state_saving::{
+        connect_to_database, create_tables_if_needed, read_room_compressor_state, try_lock_room,
+        unlock_room,
+    },
 };
-use synapse_compress_state::Level;
+use synapse_compress_state::{CompressorError, Level, TlsConfig};
 
 #[test]
 #[serial(db)]
@@ -31,7 +39,7 @@ fn run_compressor_on_room_chunk_works() {
     empty_database();
     add_contents_to_database("room1", &initial);
 
-    let mut client = connect_to_database(DB_URL).unwrap();
+    let mut client = connect_to_database(DB_URL, &TlsConfig::default()).unwrap();
     create_tables_if_needed(&mut client).unwrap();
     clear_compressor_state();
 
@@ -45,11 +53,11 @@ fn run_compressor_on_room_chunk_works() {
     // 0  3\
     // 1  4 6
     // 2  5
-    run_compressor_on_room_chunk(DB_URL, "room1", 7, &default_levels).unwrap();
+    run_compressor_on_room_chunk(&mut client, "room1", 7, &default_levels, false, false).unwrap();
 
     // compress the next 7 groups
 
-    run_compressor_on_room_chunk(DB_URL, "room1", 7, &default_levels).unwrap();
+    run_compressor_on_room_chunk(&mut client, "room1", 7, &default_levels, false, false).unwrap();
 
     // This should have created the following structure in the database
     // i.e. groups 6 and 9 should have changed from before
@@ -69,6 +77,133 @@ fn run_compressor_on_room_chunk_works() {
     assert!(database_structure_matches_map(&expected));
 }
 
+#[test]
+#[serial(db)]
+fn run_compressor_on_room_chunk_atomic_works() {
+    setup_logger();
+    // This starts with the following structure
+    //
+    // 0-1-2 3-4-5 6-7-8 9-10-11 12-13
+    //
+    // Each group i has state:
+    //     ('node','is',      i)
+    //     ('group',  j, 'seen') - for all j less than i
+    let initial = line_segments_with_state(0, 13);
+    empty_database();
+    add_contents_to_database("room1", &initial);
+
+    let mut client = connect_to_database(DB_URL, &TlsConfig::default()).unwrap();
+    create_tables_if_needed(&mut client).unwrap();
+    clear_compressor_state();
+
+    // compress in 3,3 level sizes by default
+    let default_levels = vec![Level::new(3), Level::new(3)];
+
+    // compress the room in two chunks of 7, each in a single transaction
+    run_compressor_on_room_chunk(&mut client, "room1", 7, &default_levels, true, false).unwrap();
+    run_compressor_on_room_chunk(&mut client, "room1", 7, &default_levels, true, false).unwrap();
+
+    // This should have created the same structure as when not using a
+    // single transaction
+    let expected = compressed_3_3_from_0_to_13_with_state();
+
+    // Check that the database still gives correct states for each group!
+    assert!(database_collapsed_states_match_map(&initial));
+
+    // Check that the structure of the database matches the expected structure
+    assert!(database_structure_matches_map(&expected));
+
+    // Check that the progress was saved as part of the transaction
+    let (last_compressed, _) = read_room_compressor_state(&mut client, "room1")
+        .unwrap()
+        .unwrap();
+    assert_eq!(last_compressed, 13);
+}
+
+#[test]
+#[serial(db)]
+fn run_compressor_on_room_chunk_atomic_rolls_back_if_state_changes() {
+    setup_logger();
+    // This starts with the following structure
+    //
+    // 0-1-2 3-4-5 6-7-8 9-10-11 12-13
+    //
+    // Each group i has state:
+    //     ('node','is',      i)
+    //     ('group',  j, 'seen') - for all j less than i
+    let initial = line_segments_with_state(0, 13);
+    empty_database();
+    add_contents_to_database("room1", &initial);
+
+    let mut client = connect_to_database(DB_URL, &TlsConfig::default()).unwrap();
+    create_tables_if_needed(&mut client).unwrap();
+    clear_compressor_state();
+
+    // Add a trigger that interferes with the state being written, so that
+    // the state in the database no longer matches what the compressor wrote
+    client
+        .batch_execute(
+            r#"
+            CREATE FUNCTION tamper_with_state() RETURNS TRIGGER AS $$
+            BEGIN
+                NEW.event_id := 'tampered';
+                RETURN NEW;
+            END;
+            $$ LANGUAGE plpgsql;
+
+            CREATE TRIGGER tamper_with_state BEFORE INSERT ON state_groups_state
+                FOR EACH ROW EXECUTE PROCEDURE tamper_with_state();
+            "#,
+        )
+        .unwrap();
+
+    let default_levels = vec![Level::new(3), Level::new(3)];
+    let result =
+        run_compressor_on_room_chunk(&mut client, "room1", 7, &default_levels, true, false);
+
+    client
+        .batch_execute(
+            r#"
+            DROP TRIGGER tamper_with_state ON state_groups_state;
+            DROP FUNCTION tamper_with_state();
+            "#,
+        )
+        .unwrap();
+
+    // The compressor should have noticed that the state was changed
+    assert!(matches!(
+        result.unwrap_err().downcast_ref::<CompressorError>(),
+        Some(CompressorError::VerificationMismatch { .. })
+    ));
+
+    // Nothing should have changed in the database
+    assert!(database_structure_matches_map(&initial));
+    assert!(read_room_compressor_state(&mut client, "room1")
+        .unwrap()
+        .is_none());
+}
+
+#[test]
+#[serial(db)]
+fn run_compressor_on_room_chunk_rejects_verify_committed_when_atomic() {
+    setup_logger();
+    let initial = line_segments_with_state(0, 13);
+    empty_database();
+    add_contents_to_database("room1", &initial);
+
+    let mut client = connect_to_database(DB_URL, &TlsConfig::default()).unwrap();
+    create_tables_if_needed(&mut client).unwrap();
+    clear_compressor_state();
+
+    let default_levels = vec![Level::new(3), Level::new(3)];
+    assert!(
+        run_compressor_on_room_chunk(&mut client, "room1", 7, &default_levels, true, true).is_err()
+    );
+
+    // Nothing should have been compressed
+    assert!(database_structure_matches_map(&initial));
+}
+
 #[test]
 #[serial(db)]
 fn compress_chunks_of_database_compresses_multiple_rooms() {
@@ -88,7 +223,7 @@ fn compress_chunks_of_database_compresses_multiple_rooms() {
     add_contents_to_database("room1", &initial1);
     add_contents_to_database("room2", &initial2);
 
-    let mut client = connect_to_database(DB_URL).unwrap();
+    let mut client = connect_to_database(DB_URL, &TlsConfig::default()).unwrap();
     create_tables_if_needed(&mut client).unwrap();
     clear_compressor_state();
 
@@ -97,7 +232,18 @@ fn compress_chunks_of_database_compresses_multiple_rooms() {
 
     // Compress 4 chunks of size 8.
     // The first two should compress room1 and the second two should compress room2
-    compress_chunks_of_database(DB_URL, 8, &default_levels, 4).unwrap();
+    compress_chunks_of_database(
+        &mut client,
+        8,
+        &default_levels,
+        4,
+        RoomSelection::LowestId.selector(),
+        &RoomFilter::default(),
+        None,
+        false,
+        false,
+    )
+    .unwrap();
 
     // We are aiming for the following structure in the database for room1
     // i.e. groups 6 and 9 should have changed from initial map
@@ -165,7 +311,7 @@ fn compress_chunks_of_database_continues_where_it_left_off() {
     add_contents_to_database("room1", &initial1);
     add_contents_to_database("room2", &initial2);
 
-    let mut client = connect_to_database(DB_URL).unwrap();
+    let mut client = connect_to_database(DB_URL, &TlsConfig::default()).unwrap();
     create_tables_if_needed(&mut client).unwrap();
     clear_compressor_state();
 
@@ -175,12 +321,67 @@ fn compress_chunks_of_database_continues_where_it_left_off() {
     // Compress chunks of various sizes:
     //
     // These two should compress room1
-    compress_chunks_of_database(DB_URL, 8, &default_levels, 1).unwrap();
-    compress_chunks_of_database(DB_URL, 100, &default_levels, 1).unwrap();
+    compress_chunks_of_database(
+        &mut client,
+        8,
+        &default_levels,
+        1,
+        RoomSelection::LowestId.selector(),
+        &RoomFilter::default(),
+        None,
+        false,
+        false,
+    )
+    .unwrap();
+    compress_chunks_of_database(
+        &mut client,
+        100,
+        &default_levels,
+        1,
+        RoomSelection::LowestId.selector(),
+        &RoomFilter::default(),
+        None,
+        false,
+        false,
+    )
+    .unwrap();
     // These three should compress room2
-    compress_chunks_of_database(DB_URL, 1, &default_levels, 2).unwrap();
-    compress_chunks_of_database(DB_URL, 5, &default_levels, 1).unwrap();
-    compress_chunks_of_database(DB_URL, 5, &default_levels, 1).unwrap();
+    compress_chunks_of_database(
+        &mut client,
+        1,
+        &default_levels,
+        2,
+        RoomSelection::LowestId.selector(),
+        &RoomFilter::default(),
+        None,
+        false,
+        false,
+    )
+    .unwrap();
+    compress_chunks_of_database(
+        &mut client,
+        5,
+        &default_levels,
+        1,
+        RoomSelection::LowestId.selector(),
+        &RoomFilter::default(),
+        None,
+        false,
+        false,
+    )
+    .unwrap();
+    compress_chunks_of_database(
+        &mut client,
+        5,
+        &default_levels,
+        1,
+        RoomSelection::LowestId.selector(),
+        &RoomFilter::default(),
+        None,
+        false,
+        false,
+    )
+    .unwrap();
 
     // We are aiming for the following structure in the database for room1
     // i.e. groups 6 and 9 should have changed from initial map
@@ -228,3 +429,316 @@ fn compress_chunks_of_database_continues_where_it_left_off() {
     // Check that the structure of the database matches the expected structure for room2
     assert!(database_structure_matches_map(&expected2));
 }
+
+#[test]
+#[serial(db)]
+fn compress_chunks_of_database_with_workers_compresses_multiple_rooms() {
+    setup_logger();
+    // This creates 2 with the following structure
+    //
+    // 0-1-2 3-4-5 6-7-8 9-10-11 12-13
+    // (with room2's numbers shifted up 14)
+    //
+    // Each group i has state:
+    //     ('node','is',      i)
+    //     ('group',  j, 'seen') - for all j less than i in that room
+    let initial1 = line_segments_with_state(0, 13);
+    let initial2 = line_segments_with_state(14, 27);
+
+    empty_database();
+    add_contents_to_database("room1", &initial1);
+    add_contents_to_database("room2", &initial2);
+
+    let mut clients = vec![
+        connect_to_database(DB_URL, &TlsConfig::default()).unwrap(),
+        connect_to_database(DB_URL, &TlsConfig::default()).unwrap(),
+    ];
+    create_tables_if_needed(&mut clients[0]).unwrap();
+    clear_compressor_state();
+
+    // compress in 3,3 level sizes by default
+    let default_levels = vec![Level::new(3), Level::new(3)];
+
+    // Compress 4 chunks of size 8 with 2 workers. Each room needs 2 chunks
+    // and the workers never compress the same room at once, so this should
+    // give the same result as compressing them one after another
+    compress_chunks_of_database_with_workers(
+        &mut clients,
+        8,
+        &default_levels,
+        4,
+        RoomSelection::LowestId.selector(),
+        &RoomFilter::default(),
+        None,
+        None,
+        false,
+        false,
+    )
+    .unwrap();
+
+    // room1 should have the usual structure for 3,3 levels
+    let expected1 = compressed_3_3_from_0_to_13_with_state();
+    assert!(database_collapsed_states_match_map(&initial1));
+    assert!(database_structure_matches_map(&expected1));
+
+    // room 2 should have the same structure but will all numbers shifted up by 14
+    let expected_edges: BTreeMap<i64, i64> = vec![
+        (15, 14),
+        (16, 15),
+        (18, 17),
+        (19, 18),
+        (20, 17),
+        (21, 20),
+        (22, 21),
+        (23, 20),
+        (24, 23),
+        (25, 24),
+        (27, 26),
+    ]
+    .into_iter()
+    .collect();
+
+    let expected2 = structure_from_edges_with_state(expected_edges, 14, 27);
+    assert!(database_collapsed_states_match_map(&initial2));
+    assert!(database_structure_matches_map(&expected2));
+
+    // and both rooms should have been compressed to the end
+    for (room_id, expected_last_compressed) in &[("room1", 13), ("room2", 27)] {
+        let (last_compressed, _) = read_room_compressor_state(&mut clients[0], room_id)
+            .unwrap()
+            .unwrap();
+        assert_eq!(last_compressed, *expected_last_compressed);
+    }
+}
+
+#[test]
+#[serial(db)]
+fn run_compressor_on_room_chunk_skips_busy_room() {
+    setup_logger();
+    // This starts with the following structure
+    //
+    // 0-1-2 3-4-5 6-7-8 9-10-11 12-13
+    //
+    // Each group i has state:
+    //     ('node','is',      i)
+    //     ('group',  j, 'seen') - for all j less than i
+    let initial = line_segments_with_state(0, 13);
+    empty_database();
+    add_contents_to_database("room1", &initial);
+
+    let mut client = connect_to_database(DB_URL, &TlsConfig::default()).unwrap();
+    create_tables_if_needed(&mut client).unwrap();
+    clear_compressor_state();
+
+    // Pretend that another compressor is working on the room
+    let mut other_client = connect_to_database(DB_URL, &TlsConfig::default()).unwrap();
+    assert!(try_lock_room(&mut other_client, "room1").unwrap());
+
+    let default_levels = vec![Level::new(3), Level::new(3)];
+
+    for atomic in &[false, true] {
+        let chunk_stats =
+            run_compressor_on_room_chunk(&mut client, "room1", 14, &default_levels, *atomic, false)
+                .unwrap()
+                .unwrap();
+        assert!(chunk_stats.room_busy);
+        assert!(!chunk_stats.commited);
+    }
+
+    // Nothing should have changed
+    assert!(database_structure_matches_map(&initial));
+    assert!(read_room_compressor_state(&mut client, "room1")
+        .unwrap()
+        .is_none());
+
+    // Once the other compressor has finished the room can be compressed
+    unlock_room(&mut other_client, "room1").unwrap();
+    let chunk_stats =
+        run_compressor_on_room_chunk(&mut client, "room1", 14, &default_levels, false, false)
+            .unwrap()
+            .unwrap();
+    assert!(!chunk_stats.room_busy);
+    assert!(database_structure_matches_map(
+        &compressed_3_3_from_0_to_13_with_state()
+    ));
+
+    // and the lock was released afterwards
+    assert!(try_lock_room(&mut other_client, "room1").unwrap());
+    unlock_room(&mut other_client, "room1").unwrap();
+}
+
+#[test]
+#[serial(db)]
+fn compress_chunks_of_database_skips_busy_rooms() {
+    setup_logger();
+    // This creates 2 rooms with the following structure
+    //
+    // 0-1-2 3-4-5 6-7-8 9-10-11 12-13
+    // (with room2's numbers shifted up 14)
+    let initial1 = line_segments_with_state(0, 13);
+    let initial2 = line_segments_with_state(14, 27);
+
+    empty_database();
+    add_contents_to_database("room1", &initial1);
+    add_contents_to_database("room2", &initial2);
+
+    let mut client = connect_to_database(DB_URL, &TlsConfig::default()).unwrap();
+    create_tables_if_needed(&mut client).unwrap();
+    clear_compressor_state();
+
+    // Pretend that another compressor is working on room1
+    let mut other_client = connect_to_database(DB_URL, &TlsConfig::default()).unwrap();
+    assert!(try_lock_room(&mut other_client, "room1").unwrap());
+
+    let default_levels = vec![Level::new(3), Level::new(3)];
+
+    // Only room2 should be compressed, and the busy room shouldn't count as
+    // one of the chunks
+    compress_chunks_of_database(
+        &mut client,
+        14,
+        &default_levels,
+        1,
+        RoomSelection::LowestId.selector(),
+        &RoomFilter::default(),
+        None,
+        false,
+        false,
+    )
+    .unwrap();
+
+    unlock_room(&mut other_client, "room1").unwrap();
+
+    assert!(database_structure_matches_map(&initial1));
+    assert!(read_room_compressor_state(&mut client, "room1")
+        .unwrap()
+        .is_none());
+
+    let (last_compressed, _) = read_room_compressor_state(&mut client, "room2")
+        .unwrap()
+        .unwrap();
+    assert_eq!(last_compressed, 27);
+    assert!(database_collapsed_states_match_map(&initial2));
+
+    // room1 is picked up again by the next run
+    compress_chunks_of_database(
+        &mut client,
+        14,
+        &default_levels,
+        1,
+        RoomSelection::LowestId.selector(),
+        &RoomFilter::default(),
+        None,
+        false,
+        false,
+    )
+    .unwrap();
+    let (last_compressed, _) = read_room_compressor_state(&mut client, "room1")
+        .unwrap()
+        .unwrap();
+    assert_eq!(last_compressed, 13);
+}
+
+#[test]
+#[serial(db)]
+fn compress_chunks_of_database_stops_when_out_of_time() {
+    setup_logger();
+    // This creates 2 rooms with the following structure
+    //
+    // 0-1-2 3-4-5 6-7-8 9-10-11 12-13
+    // (with room2's numbers shifted up 14)
+    let initial1 = line_segments_with_state(0, 13);
+    let initial2 = line_segments_with_state(14, 27);
+
+    empty_database();
+    add_contents_to_database("room1", &initial1);
+    add_contents_to_database("room2", &initial2);
+
+    let mut client = connect_to_database(DB_URL, &TlsConfig::default()).unwrap();
+    create_tables_if_needed(&mut client).unwrap();
+    clear_compressor_state();
+
+    let default_levels = vec![Level::new(3), Level::new(3)];
+
+    // There's no time for any chunks so nothing should be compressed
+    compress_chunks_of_database(
+        &mut client,
+        14,
+        &default_levels,
+        2,
+        RoomSelection::LowestId.selector(),
+        &RoomFilter::default(),
+        Some(Duration::ZERO),
+        false,
+        false,
+    )
+    .unwrap();
+
+    assert!(database_structure_matches_map(&initial1));
+    assert!(read_room_compressor_state(&mut client, "room1")
+        .unwrap()
+        .is_none());
+    assert!(read_room_compressor_state(&mut client, "room2")
+        .unwrap()
+        .is_none());
+
+    // With plenty of time both rooms should be compressed
+    compress_chunks_of_database(
+        &mut client,
+        14,
+        &default_levels,
+        2,
+        RoomSelection::LowestId.selector(),
+        &RoomFilter::default(),
+        Some(Duration::from_secs(60 * 60)),
+        false,
+        false,
+    )
+    .unwrap();
+
+    for (room_id, last_group) in [("room1", 13), ("room2", 27)] {
+        let (last_compressed, _) = read_room_compressor_state(&mut client, room_id)
+            .unwrap()
+            .unwrap();
+        assert_eq!(last_compressed, last_group);
+    }
+    assert!(database_collapsed_states_match_map(&initial1));
+    assert!(database_collapsed_states_match_map(&initial2));
+}
+
+#[test]
+#[serial(db)]
+fn compress_chunks_of_database_with_workers_does_nothing_once_stopped() {
+    setup_logger();
+    let initial1 = line_segments_with_state(0, 13);
+
+    empty_database();
+    add_contents_to_database("room1", &initial1);
+
+    let mut clients = vec![connect_to_database(DB_URL, &TlsConfig::default()).unwrap()];
+    create_tables_if_needed(&mut clients[0]).unwrap();
+    clear_compressor_state();
+
+    let default_levels = vec![Level::new(3), Level::new(3)];
+
+    let run_stats = compress_chunks_of_database_with_workers(
+        &mut clients,
+        14,
+        &default_levels,
+        1,
+        RoomSelection::LowestId.selector(),
+        &RoomFilter::default(),
+        None,
+        Some(&AtomicBool::new(true)),
+        false,
+        false,
+    )
+    .unwrap();
+
+    assert_eq!(run_stats.chunks_processed, 0);
+    assert!(!run_stats.out_of_rooms);
+    assert!(database_structure_matches_map(&initial1));
+    assert!(read_room_compressor_state(&mut clients[0], "room1")
+        .unwrap()
+        .is_none());
+}
@@ -0,0 +1,126 @@
+use std::{
+    fs,
+    io::{Read, Write},
+    net::TcpStream,
+};
+
+use compressor_integration_tests::{
+    add_contents_to_database, clear_compressor_state, empty_database,
+    map_builder::line_segments_with_state, setup_logger, DB_URL,
+};
+use serial_test::serial;
+use synapse_auto_compressor::{
+    manager::compress_chunks_of_database,
+    metrics::{self, METRICS},
+    room_filter::RoomFilter,
+    room_selection::RoomSelection,
+    state_saving::{connect_to_database, create_tables_if_needed},
+};
+use synapse_compress_state::{Level, TlsConfig};
+
+/// Finds the value of a metric in the output of `METRICS.render()`
+fn metric_value(name: &str) -> Option<f64> {
+    METRICS.render().lines().find_map(|line| {
+        line.strip_prefix(name)
+            .and_then(|value| value.strip_prefix(' '))
+            .map(|value| value.parse().unwrap())
+    })
+}
+
+/// Sends a GET request for `path` and returns the response
+fn get(address: &str, path: &str) -> String {
+    let mut stream = TcpStream::connect(address).unwrap();
+    write!(stream, "GET {} HTTP/1.1\r\nHost: {}\r\n\r\n", path, address).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[test]
+#[serial(db)]
+fn compress_chunks_of_database_records_metrics() {
+    setup_logger();
+    // This starts with the following structure
+    //
+    // 0-1-2 3-4-5 6-7-8 9-10-11 12-13
+    let initial = line_segments_with_state(0, 13);
+
+    empty_database();
+    add_contents_to_database("room1", &initial);
+
+    let mut client = connect_to_database(DB_URL, &TlsConfig::default()).unwrap();
+    create_tables_if_needed(&mut client).unwrap();
+    clear_compressor_state();
+
+    let default_levels = vec![Level::new(3), Level::new(3)];
+
+    let counter =
+        |name: &str| metric_value(&format!("synapse_auto_compressor_{}_total", name)).unwrap();
+    let processed = counter("chunks_processed");
+    let committed = counter("chunks_committed");
+    let skipped = counter("chunks_skipped");
+    let rows_before = counter("rows_before");
+    let rows_after = counter("rows_after");
+    let errors = counter("errors");
+
+    // The whole room fits in one chunk, so this commits one chunk
+    let run_stats = compress_chunks_of_database(
+        &mut client,
+        14,
+        &default_levels,
+        1,
+        RoomSelection::LowestId.selector(),
+        &RoomFilter::default(),
+        None,
+        false,
+        false,
+    )
+    .unwrap();
+
+    assert_eq!(counter("chunks_processed"), processed + 1.0);
+    assert_eq!(counter("chunks_committed"), committed + 1.0);
+    assert_eq!(counter("chunks_skipped"), skipped);
+    assert_eq!(counter("errors"), errors);
+    assert_eq!(
+        (counter("rows_before") - rows_before) - (counter("rows_after") - rows_after),
+        run_stats.rows_saved as f64
+    );
+    assert!(run_stats.rows_saved > 0);
+
+    // room1 was picked starting from group 0
+    assert_eq!(
+        metric_value("synapse_auto_compressor_lowest_uncompressed_group"),
+        Some(0.0)
+    );
+    assert!(METRICS
+        .render()
+        .contains("synapse_auto_compressor_phase_seconds_total{phase=\"compress\"}"));
+}
+
+#[test]
+fn serve_answers_metrics_requests() {
+    let address = metrics::serve("127.0.0.1:0").unwrap().to_string();
+
+    let response = get(&address, "/metrics");
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    assert!(response.contains("# TYPE synapse_auto_compressor_chunks_processed_total counter"));
+
+    let response = get(&address, "/something-else");
+    assert!(response.starts_with("HTTP/1.1 404"), "{}", response);
+}
+
+#[test]
+fn write_textfile_writes_metrics() {
+    let dir = std::env::temp_dir().join(format!("compressor-metrics-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("synapse_auto_compressor.prom");
+
+    metrics::write_textfile(&path).unwrap();
+
+    let contents = fs::read_to_string(&path).unwrap();
+    assert!(contents.contains("synapse_auto_compressor_rows_before_total"));
+    // The temporary file is renamed over the real one
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
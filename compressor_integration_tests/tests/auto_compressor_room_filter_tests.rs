@@ -0,0 +1,295 @@
+use std::{env, fs};
+
+use compressor_integration_tests::{
+    add_contents_to_database, clear_compressor_state, database_collapsed_states_match_map,
+    empty_database, map_builder::line_segments_with_state, setup_logger, DB_URL,
+};
+use postgres::Client;
+use serial_test::serial;
+use synapse_auto_compressor::{
+    manager::compress_chunks_of_database,
+    room_filter::{read_room_list, RoomFilter},
+    room_selection::{RoomSelection, ROOM_SELECTIONS},
+    state_saving::{
+        connect_to_database, create_tables_if_needed, get_next_room_to_compress,
+        read_room_compressor_state,
+    },
+};
+use synapse_compress_state::{Level, TlsConfig};
+
+const AUDIT_ROOM: &str = "!audit:example.com";
+const PUBLIC_ROOM: &str = "!public:example.com";
+const REMOTE_ROOM: &str = "!remote:matrix.org";
+
+fn patterns(patterns: &[&str]) -> Vec<String> {
+    patterns.iter().map(|p| p.to_string()).collect()
+}
+
+fn filter(include: &[&str], exclude: &[&str]) -> RoomFilter {
+    RoomFilter::new(patterns(include), patterns(exclude)).unwrap()
+}
+
+/// Creates 3 rooms, in the order AUDIT_ROOM, PUBLIC_ROOM, REMOTE_ROOM
+/// (each with 14 state groups)
+fn setup_rooms() -> Client {
+    setup_logger();
+    empty_database();
+    add_contents_to_database(AUDIT_ROOM, &line_segments_with_state(0, 13));
+    add_contents_to_database(PUBLIC_ROOM, &line_segments_with_state(14, 27));
+    add_contents_to_database(REMOTE_ROOM, &line_segments_with_state(28, 41));
+
+    let mut client = connect_to_database(DB_URL, &TlsConfig::default()).unwrap();
+    create_tables_if_needed(&mut client).unwrap();
+    clear_compressor_state();
+    client
+}
+
+fn lowest_uncompressed_group(client: &mut Client) -> i64 {
+    client
+        .query_one(
+            "SELECT lowest_uncompressed_group FROM state_compressor_total_progress",
+            &[],
+        )
+        .unwrap()
+        .get(0)
+}
+
+#[test]
+fn room_filter_matches_room_ids_and_server_names() {
+    let room_filter = filter(&[], &[AUDIT_ROOM, "*.example.org", "matrix.?rg"]);
+
+    assert!(!room_filter.allows(AUDIT_ROOM));
+    assert!(room_filter.allows(PUBLIC_ROOM));
+    assert!(!room_filter.allows(REMOTE_ROOM));
+    assert!(!room_filter.allows("!abc:chat.example.org"));
+    // The glob has to match the whole server name
+    assert!(room_filter.allows("!abc:example.org"));
+    assert!(room_filter.allows("!abc:matrix.org.uk"));
+
+    let room_filter = filter(&["example.com"], &[AUDIT_ROOM]);
+    assert!(!room_filter.allows(AUDIT_ROOM));
+    assert!(room_filter.allows(PUBLIC_ROOM));
+    assert!(!room_filter.allows(REMOTE_ROOM));
+
+    assert!(RoomFilter::default().allows(REMOTE_ROOM));
+}
+
+#[test]
+fn room_filter_matches_globs_with_many_stars() {
+    // This would take exponential time if every way of matching the stars
+    // against the server name was tried
+    let server_name = "a".repeat(60);
+    let room_filter = filter(&[&format!("{}b", "*a".repeat(20))], &[]);
+    assert!(!room_filter.allows(&format!("!abc:{}", server_name)));
+    assert!(room_filter.allows(&format!("!abc:{}b", server_name)));
+
+    let room_filter = filter(&["*.*.example.*"], &[]);
+    assert!(room_filter.allows("!abc:a.b.example.org"));
+    assert!(room_filter.allows("!abc:.b.example."));
+    assert!(!room_filter.allows("!abc:b.example.org"));
+    assert!(!room_filter.allows("!abc:a.b.example"));
+}
+
+#[test]
+fn room_filter_matches_non_ascii_server_names() {
+    // `?` matches a whole character, even one that is several bytes long
+    let room_filter = filter(&["b?cher.example"], &[]);
+    assert!(room_filter.allows("!abc:bücher.example"));
+    assert!(room_filter.allows("!abc:bucher.example"));
+    assert!(!room_filter.allows("!abc:bcher.example"));
+
+    let room_filter = filter(&["*.bücher.example"], &[]);
+    assert!(room_filter.allows("!abc:ä.bücher.example"));
+    assert!(!room_filter.allows("!abc:bücher.example"));
+}
+
+#[test]
+fn room_filter_rejects_invalid_patterns() {
+    assert!(RoomFilter::new(patterns(&["!abc"]), vec![]).is_err());
+    assert!(RoomFilter::new(vec![], patterns(&[""])).is_err());
+}
+
+#[test]
+fn room_filter_fingerprint_ignores_order() {
+    assert_eq!(
+        filter(&["a.com", "b.com"], &[AUDIT_ROOM]).fingerprint(),
+        filter(&["b.com", "a.com", "a.com"], &[AUDIT_ROOM]).fingerprint()
+    );
+    assert_ne!(
+        filter(&["a.com"], &[]).fingerprint(),
+        filter(&[], &["a.com"]).fingerprint()
+    );
+    assert_eq!(RoomFilter::default().fingerprint(), "");
+}
+
+#[test]
+#[serial(db)]
+fn room_filter_regexes_match_the_same_rooms_in_postgres() {
+    let mut client = connect_to_database(DB_URL, &TlsConfig::default()).unwrap();
+
+    let room_patterns = [
+        AUDIT_ROOM,
+        "example.com",
+        "*.example.org",
+        "matrix.?rg",
+        "[weird].host",
+        "!a.b+c:d(e)",
+        "b?cher.example",
+    ];
+    let room_ids = [
+        AUDIT_ROOM,
+        PUBLIC_ROOM,
+        REMOTE_ROOM,
+        "!abc:chat.example.org",
+        "!abc:example.org",
+        "!abc:matrixxorg",
+        "!abc:[weird].host",
+        "!abc:w.host",
+        "!a.b+c:d(e)",
+        "!aXbbc:d(e)",
+        "!abc:bücher.example",
+        "!abc:bcher.example",
+    ];
+
+    for pattern in room_patterns {
+        let room_filter = filter(&[pattern], &[]);
+        let regexes = room_filter.include_regexes();
+        for room_id in room_ids {
+            let matches: bool = client
+                .query_one("SELECT $1 ~ ANY($2)", &[&room_id, &regexes])
+                .unwrap()
+                .get(0);
+            assert_eq!(
+                matches,
+                room_filter.allows(room_id),
+                "{} and {} disagree",
+                pattern,
+                room_id
+            );
+        }
+    }
+}
+
+#[test]
+#[serial(db)]
+fn get_next_room_to_compress_skips_excluded_rooms() {
+    let mut client = setup_rooms();
+    let room_filter = filter(&[], &[AUDIT_ROOM]);
+
+    assert_eq!(
+        get_next_room_to_compress(&mut client, &[], &room_filter).unwrap(),
+        Some(PUBLIC_ROOM.to_string())
+    );
+    // The excluded room's groups don't hold back the total progress
+    assert_eq!(lowest_uncompressed_group(&mut client), 14);
+
+    let room_filter = filter(&["matrix.org"], &[]);
+    assert_eq!(
+        get_next_room_to_compress(&mut client, &[], &room_filter).unwrap(),
+        Some(REMOTE_ROOM.to_string())
+    );
+
+    let room_filter = filter(&["example.com"], &["example.com"]);
+    assert_eq!(
+        get_next_room_to_compress(&mut client, &[], &room_filter).unwrap(),
+        None
+    );
+}
+
+#[test]
+#[serial(db)]
+fn get_next_room_to_compress_starts_again_when_filter_changes() {
+    let mut client = setup_rooms();
+
+    let room_filter = filter(&[], &["example.com"]);
+    assert_eq!(
+        get_next_room_to_compress(&mut client, &[], &room_filter).unwrap(),
+        Some(REMOTE_ROOM.to_string())
+    );
+    assert_eq!(lowest_uncompressed_group(&mut client), 28);
+
+    // The rooms that were skipped are found again once they're allowed
+    assert_eq!(
+        get_next_room_to_compress(&mut client, &[], &RoomFilter::default()).unwrap(),
+        Some(AUDIT_ROOM.to_string())
+    );
+    assert_eq!(lowest_uncompressed_group(&mut client), 0);
+}
+
+#[test]
+#[serial(db)]
+fn room_selections_honour_room_filter() {
+    let mut client = setup_rooms();
+    let room_filter = filter(&["example.com"], &[PUBLIC_ROOM]);
+
+    for room_selection in ROOM_SELECTIONS {
+        let selector = room_selection.parse::<RoomSelection>().unwrap().selector();
+        assert_eq!(
+            selector.next_room(&mut client, &[], &room_filter).unwrap(),
+            Some(AUDIT_ROOM.to_string()),
+            "{} picked the wrong room",
+            room_selection
+        );
+        assert_eq!(
+            selector
+                .next_room(&mut client, &[AUDIT_ROOM.to_string()], &room_filter)
+                .unwrap(),
+            None,
+            "{} picked a room that isn't allowed",
+            room_selection
+        );
+    }
+}
+
+#[test]
+#[serial(db)]
+fn compress_chunks_of_database_leaves_excluded_rooms_alone() {
+    let mut client = setup_rooms();
+    let default_levels = vec![Level::new(3), Level::new(3)];
+
+    let run_stats = compress_chunks_of_database(
+        &mut client,
+        100,
+        &default_levels,
+        10,
+        RoomSelection::LowestId.selector(),
+        &filter(&[], &[AUDIT_ROOM]),
+        None,
+        false,
+        false,
+    )
+    .unwrap();
+    assert!(run_stats.out_of_rooms);
+
+    assert!(read_room_compressor_state(&mut client, AUDIT_ROOM)
+        .unwrap()
+        .is_none());
+    for room_id in [PUBLIC_ROOM, REMOTE_ROOM] {
+        assert!(read_room_compressor_state(&mut client, room_id)
+            .unwrap()
+            .is_some());
+    }
+
+    // Compressing hasn't changed the state of any of the rooms
+    for (start, end) in [(0, 13), (14, 27), (28, 41)] {
+        assert!(database_collapsed_states_match_map(
+            &line_segments_with_state(start, end)
+        ));
+    }
+}
+
+#[test]
+fn read_room_list_ignores_comments_and_blank_lines() {
+    let path = env::temp_dir().join(format!("room_list_{}.txt", std::process::id()));
+    fs::write(
+        &path,
+        "# Rooms under legal hold\n!audit:example.com\n\n  *.example.org  # and subdomains\n",
+    )
+    .unwrap();
+
+    let room_list = read_room_list(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    assert_eq!(room_list, patterns(&[AUDIT_ROOM, "*.example.org"]));
+    assert!(read_room_list(&path).is_err());
+}
@@ -0,0 +1,258 @@
+use compressor_integration_tests::{
+    add_contents_to_database, clear_compressor_state, empty_database,
+    map_builder::{line_segments_with_state, line_with_state},
+    setup_logger, DB_URL,
+};
+use postgres::Client;
+use serial_test::serial;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use synapse_auto_compressor::{
+    manager::compress_chunks_of_database,
+    room_filter::RoomFilter,
+    room_selection::{RoomSelection, RoomSelector, ROOM_SELECTIONS},
+    state_saving::{
+        connect_to_database, create_tables_if_needed, read_room_compressor_state,
+        write_room_compressor_state,
+    },
+};
+use synapse_compress_state::{Level, TlsConfig};
+
+/// Creates 4 rooms that each of the built in strategies picks differently
+/// (each state group has 2 rows of state, plus the full state for snapshots)
+///
+/// room1: 0-1-2                        3 groups, 6 rows
+/// room2: 3-4-5-6-7-8-9-10-11-12      10 groups, 20 rows
+/// room3: 13-14-15 16-17-18 19-20-21   9 groups, 27 rows, compressed up to 19
+/// room4: 22-23-24 25-26-27 28-29      8 groups, 25 rows
+fn setup_rooms() -> Client {
+    setup_logger();
+    empty_database();
+    add_contents_to_database("room1", &line_with_state(0, 2));
+    add_contents_to_database("room2", &line_with_state(3, 12));
+    add_contents_to_database("room3", &line_segments_with_state(13, 21));
+    add_contents_to_database("room4", &line_segments_with_state(22, 29));
+
+    let mut client = connect_to_database(DB_URL, &TlsConfig::default()).unwrap();
+    create_tables_if_needed(&mut client).unwrap();
+    clear_compressor_state();
+
+    write_room_compressor_state(&mut client, "room3", &[Level::new(3)], 19).unwrap();
+
+    client
+}
+
+fn next_room(
+    client: &mut Client,
+    room_selection: &str,
+    rooms_in_progress: &[&str],
+) -> Option<String> {
+    let rooms_in_progress: Vec<String> = rooms_in_progress.iter().map(|r| r.to_string()).collect();
+    room_selection
+        .parse::<RoomSelection>()
+        .unwrap()
+        .selector()
+        .next_room(client, &rooms_in_progress, &RoomFilter::default())
+        .unwrap()
+}
+
+#[test]
+#[serial(db)]
+fn each_room_selection_picks_the_expected_room() {
+    let mut client = setup_rooms();
+
+    assert_eq!(
+        next_room(&mut client, "lowest-id", &[]),
+        Some("room1".to_string())
+    );
+    assert_eq!(
+        next_room(&mut client, "largest", &[]),
+        Some("room3".to_string())
+    );
+    assert_eq!(
+        next_room(&mut client, "most-uncompressed", &[]),
+        Some("room2".to_string())
+    );
+    // room4 has 25 - 8 = 17 rows that could be saved, room2 has 20 - 10 = 10
+    // and the 2 uncompressed groups of room3 only have 4 - 2 = 2
+    assert_eq!(
+        next_room(&mut client, "estimated-savings", &[]),
+        Some("room4".to_string())
+    );
+}
+
+#[test]
+#[serial(db)]
+fn room_selections_skip_rooms_in_progress() {
+    let mut client = setup_rooms();
+
+    assert_eq!(
+        next_room(&mut client, "largest", &["room3"]),
+        Some("room4".to_string())
+    );
+    assert_eq!(
+        next_room(&mut client, "most-uncompressed", &["room2"]),
+        Some("room4".to_string())
+    );
+    assert_eq!(
+        next_room(&mut client, "estimated-savings", &["room4", "room2"]),
+        Some("room1".to_string())
+    );
+}
+
+#[test]
+#[serial(db)]
+fn room_selections_ignore_compressed_rooms() {
+    let mut client = setup_rooms();
+
+    for (room_id, last_group) in [("room1", 2), ("room2", 12), ("room3", 21), ("room4", 29)] {
+        write_room_compressor_state(&mut client, room_id, &[Level::new(3)], last_group).unwrap();
+    }
+
+    for room_selection in ROOM_SELECTIONS {
+        assert_eq!(
+            next_room(&mut client, room_selection, &[]),
+            None,
+            "{} picked a compressed room",
+            room_selection
+        );
+    }
+}
+
+#[test]
+fn unknown_room_selection_is_rejected() {
+    assert!("smallest".parse::<RoomSelection>().is_err());
+}
+
+/// A strategy that isn't built in, to check that the trait can be implemented
+/// outside of the crate
+struct Fixed(&'static str);
+
+impl RoomSelector for Fixed {
+    fn next_room(
+        &self,
+        _client: &mut Client,
+        rooms_in_progress: &[String],
+        _room_filter: &RoomFilter,
+    ) -> anyhow::Result<Option<String>> {
+        if rooms_in_progress.iter().any(|room| room == self.0) {
+            Ok(None)
+        } else {
+            Ok(Some(self.0.to_string()))
+        }
+    }
+}
+
+#[test]
+#[serial(db)]
+fn compress_chunks_of_database_uses_custom_room_selector() {
+    let mut client = setup_rooms();
+    let default_levels = vec![Level::new(3), Level::new(3)];
+
+    compress_chunks_of_database(
+        &mut client,
+        100,
+        &default_levels,
+        1,
+        &Fixed("room2"),
+        &RoomFilter::default(),
+        None,
+        false,
+        false,
+    )
+    .unwrap();
+
+    let (last_compressed, _) = read_room_compressor_state(&mut client, "room2")
+        .unwrap()
+        .unwrap();
+    assert_eq!(last_compressed, 12);
+    assert!(read_room_compressor_state(&mut client, "room1")
+        .unwrap()
+        .is_none());
+}
+
+/// A strategy that ranks the rooms, counting how many times it has been asked to
+struct Ranked {
+    rooms: &'static [&'static str],
+    rankings: AtomicUsize,
+}
+
+impl RoomSelector for Ranked {
+    fn rank_rooms(
+        &self,
+        _client: &mut Client,
+        _room_filter: &RoomFilter,
+    ) -> anyhow::Result<Option<Vec<String>>> {
+        self.rankings.fetch_add(1, Ordering::SeqCst);
+        Ok(Some(self.rooms.iter().map(|r| r.to_string()).collect()))
+    }
+}
+
+#[test]
+#[serial(db)]
+fn compress_chunks_of_database_ranks_rooms_once_per_run() {
+    let mut client = setup_rooms();
+    let default_levels = vec![Level::new(3), Level::new(3)];
+    let selector = Ranked {
+        rooms: &["room2", "room1"],
+        rankings: AtomicUsize::new(0),
+    };
+
+    // room2 is finished by the first chunk, so is dropped from the ranking
+    // when it is picked again and room1 is compressed next
+    let run_stats = compress_chunks_of_database(
+        &mut client,
+        100,
+        &default_levels,
+        2,
+        &selector,
+        &RoomFilter::default(),
+        None,
+        false,
+        false,
+    )
+    .unwrap();
+
+    assert_eq!(run_stats.chunks_processed, 2);
+    assert_eq!(selector.rankings.load(Ordering::SeqCst), 1);
+    for (room_id, last_group) in [("room1", 2), ("room2", 12)] {
+        let (last_compressed, _) = read_room_compressor_state(&mut client, room_id)
+            .unwrap()
+            .unwrap();
+        assert_eq!(last_compressed, last_group);
+    }
+    assert!(read_room_compressor_state(&mut client, "room4")
+        .unwrap()
+        .is_none());
+}
+
+#[test]
+#[serial(db)]
+fn compress_chunks_of_database_compresses_every_ranked_room() {
+    for room_selection in ["largest", "most-uncompressed", "estimated-savings"] {
+        let mut client = setup_rooms();
+        let default_levels = vec![Level::new(3), Level::new(3)];
+
+        let run_stats = compress_chunks_of_database(
+            &mut client,
+            4,
+            &default_levels,
+            100,
+            room_selection.parse::<RoomSelection>().unwrap().selector(),
+            &RoomFilter::default(),
+            None,
+            false,
+            false,
+        )
+        .unwrap();
+        assert!(run_stats.out_of_rooms, "{} didn't finish", room_selection);
+
+        for room_selection in ROOM_SELECTIONS {
+            assert_eq!(
+                next_room(&mut client, room_selection, &[]),
+                None,
+                "{} found a room to compress",
+                room_selection
+            );
+        }
+    }
+}
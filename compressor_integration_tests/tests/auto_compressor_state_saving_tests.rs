@@ -1,16 +1,22 @@
-use compressor_integration_tests::{clear_compressor_state, setup_logger, DB_URL};
+use compressor_integration_tests::{
+    add_contents_to_database, clear_compressor_state, empty_database,
+    map_builder::line_segments_with_state, setup_logger, DB_URL,
+};
 use serial_test::serial;
-use synapse_auto_compressor::state_saving::{
-    connect_to_database, create_tables_if_needed, read_room_compressor_state,
-    write_room_compressor_state,
+use synapse_auto_compressor::{
+    room_filter::RoomFilter,
+    state_saving::{
+        connect_to_database, create_tables_if_needed, get_next_room_to_compress,
+        read_room_compressor_state, write_room_compressor_state,
+    },
 };
-use synapse_compress_state::Level;
+use synapse_compress_state::{Level, TlsConfig};
 
 #[test]
 #[serial(db)]
 fn write_then_read_state_gives_correct_results() {
     setup_logger();
-    let mut client = connect_to_database(DB_URL).unwrap();
+    let mut client = connect_to_database(DB_URL, &TlsConfig::default()).unwrap();
     create_tables_if_needed(&mut client).unwrap();
     clear_compressor_state();
 
@@ -27,3 +33,48 @@ fn write_then_read_state_gives_correct_results() {
     assert_eq!(written_info, read_info);
     assert_eq!(written_num, read_num);
 }
+
+#[test]
+#[serial(db)]
+fn get_next_room_to_compress_skips_rooms_in_progress() {
+    setup_logger();
+    empty_database();
+    add_contents_to_database("room1", &line_segments_with_state(0, 13));
+    add_contents_to_database("room2", &line_segments_with_state(14, 27));
+
+    let mut client = connect_to_database(DB_URL, &TlsConfig::default()).unwrap();
+    create_tables_if_needed(&mut client).unwrap();
+    clear_compressor_state();
+
+    let in_progress =
+        |rooms: &[&str]| -> Vec<String> { rooms.iter().map(|room| room.to_string()).collect() };
+
+    assert_eq!(
+        get_next_room_to_compress(&mut client, &[], &RoomFilter::default()).unwrap(),
+        Some("room1".to_string())
+    );
+    assert_eq!(
+        get_next_room_to_compress(
+            &mut client,
+            &in_progress(&["room1"]),
+            &RoomFilter::default()
+        )
+        .unwrap(),
+        Some("room2".to_string())
+    );
+    assert_eq!(
+        get_next_room_to_compress(
+            &mut client,
+            &in_progress(&["room1", "room2"]),
+            &RoomFilter::default()
+        )
+        .unwrap(),
+        None
+    );
+
+    // Skipping room1 mustn't have moved the total progress past its groups
+    assert_eq!(
+        get_next_room_to_compress(&mut client, &[], &RoomFilter::default()).unwrap(),
+        Some("room1".to_string())
+    );
+}
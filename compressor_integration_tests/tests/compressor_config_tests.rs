@@ -1,4 +1,4 @@
-use std::collections::BTreeMap;
+use std::{collections::BTreeMap, fs};
 
 use compressor_integration_tests::{
     add_contents_to_database, database_collapsed_states_match_map, database_structure_matches_map,
@@ -10,7 +10,7 @@ use compressor_integration_tests::{
     setup_logger, DB_URL,
 };
 use serial_test::serial;
-use synapse_compress_state::{run, Config};
+use synapse_compress_state::{connect_to_database, run, CompressorError, Config, TlsConfig};
 
 // Remember to add #[serial(db)] before any test that access the database.
 // Only one test with this annotation can run at once - preventing
@@ -61,10 +61,17 @@ fn run_succeeds_without_crashing() {
         graphs,
         commit_changes,
         verify,
+        TlsConfig::default(),
+        false,
+        None,
+        "query".to_string(),
+        None,
+        0.0,
+        "ancestors".to_string(),
     )
     .unwrap();
 
-    run(config);
+    run(config).unwrap();
 }
 
 #[test]
@@ -111,11 +118,18 @@ fn changes_commited_if_no_min_saved_rows() {
         graphs,
         commit_changes,
         verify,
+        TlsConfig::default(),
+        false,
+        None,
+        "query".to_string(),
+        None,
+        0.0,
+        "ancestors".to_string(),
     )
     .unwrap();
 
     // Run the compressor with those settings
-    run(config);
+    run(config).unwrap();
 
     // This should have created the following structure in the database
     // i.e. groups 6 and 9 should have changed from before
@@ -179,11 +193,18 @@ fn changes_commited_if_min_saved_rows_exceeded() {
         graphs,
         commit_changes,
         verify,
+        TlsConfig::default(),
+        false,
+        None,
+        "query".to_string(),
+        None,
+        0.0,
+        "ancestors".to_string(),
     )
     .unwrap();
 
     // Run the compressor with those settings
-    run(config);
+    run(config).unwrap();
 
     // This should have created the following structure in the database
     // i.e. groups 6 and 9 should have changed from before
@@ -248,11 +269,18 @@ fn changes_not_commited_if_fewer_than_min_saved_rows() {
         graphs,
         commit_changes,
         verify,
+        TlsConfig::default(),
+        false,
+        None,
+        "query".to_string(),
+        None,
+        0.0,
+        "ancestors".to_string(),
     )
     .unwrap();
 
     // Run the compressor with those settings
-    run(config);
+    run(config).unwrap();
 
     // This should have created the following structure when running
     // (i.e. try and change groups 6 and 9 only)
@@ -273,13 +301,89 @@ fn changes_not_commited_if_fewer_than_min_saved_rows() {
 }
 
 #[test]
-#[should_panic(expected = "Error connecting to the database:")]
-fn run_panics_if_invalid_db_url() {
+fn changes_not_commited_if_cost_not_reduced_enough() {
+    setup_logger();
+    // This starts with the following structure
+    //
+    // 0-1-2 3-4-5 6-7-8 9-10-11 12-13
+    //
+    // Each group i has state:
+    //     ('node','is',      i)
+    //     ('group',  j, 'seen') - for all j less than i
+    let initial = line_segments_with_state(0, 13);
+
+    // Place this initial state into an empty database
+    empty_database();
+    add_contents_to_database("room1", &initial);
+
+    // set up the config options
+    let db_url = DB_URL.to_string();
+    let room_id = "room1".to_string();
+    let output_file =
+        Some("./tests/tmp/changes_not_commited_if_cost_not_reduced_enough.sql".to_string());
+    let min_state_group = None;
+    let min_saved_rows = None;
+    let min_saved_cost = Some(0.0);
+    let depth_weight = 2.0;
+    let groups_to_compress = None;
+    let max_state_group = None;
+    let level_sizes = "3,3".to_string();
+    let transactions = true;
+    let graphs = false;
+    let commit_changes = true;
+    let verify = true;
+
+    let config = Config::new(
+        db_url,
+        room_id,
+        output_file,
+        min_state_group,
+        groups_to_compress,
+        min_saved_rows,
+        max_state_group,
+        level_sizes,
+        transactions,
+        graphs,
+        commit_changes,
+        verify,
+        TlsConfig::default(),
+        false,
+        None,
+        "query".to_string(),
+        min_saved_cost,
+        depth_weight,
+        "ancestors".to_string(),
+    )
+    .unwrap();
+
+    // Run the compressor with those settings
+    run(config).unwrap();
+
+    // This would create the following structure, which saves 11 rows but
+    // adds 9 to the sum of the depths of the groups (from 13 to 22). With a
+    // depth weight of 2 this increases the cost, so nothing should be
+    // committed.
+    //
+    // 0  3\      12
+    // 1  4 6\    13
+    // 2  5 7 9
+    //      8 10
+    //        11
+
+    // Check that the database still gives correct states for each group!
+    assert!(database_collapsed_states_match_map(&initial));
+
+    // Check that the structure of the database matches the expected structure
+    assert!(database_structure_matches_map(&initial));
+}
+
+#[test]
+fn run_errors_if_invalid_db_url() {
     setup_logger();
     // set up the config options
     let db_url = "thisIsAnInvalidURL".to_string();
     let room_id = "room1".to_string();
-    let output_file = Some("./tests/tmp/run_panics_if_invalid_db_url.sql".to_string());
+    let output_file = Some("./tests/tmp/run_errors_if_invalid_db_url.sql".to_string());
     let min_state_group = None;
     let min_saved_rows = None;
     let groups_to_compress = None;
@@ -303,11 +407,18 @@ fn run_panics_if_invalid_db_url() {
         graphs,
         commit_changes,
         verify,
+        TlsConfig::default(),
+        false,
+        None,
+        "query".to_string(),
+        None,
+        0.0,
+        "ancestors".to_string(),
     )
     .unwrap();
 
     // Run the compressor with those settings
-    run(config);
+    assert!(matches!(run(config), Err(CompressorError::Connect(_))));
 }
 
 #[test]
@@ -361,11 +472,18 @@ fn run_only_affects_given_room_id() {
         graphs,
         commit_changes,
         verify,
+        TlsConfig::default(),
+        false,
+        None,
+        "query".to_string(),
+        None,
+        0.0,
+        "ancestors".to_string(),
     )
     .unwrap();
 
     // Run the compressor with those settings
-    run(config);
+    run(config).unwrap();
 
     // This should have created the following structure in the database
     // i.e. groups 6 and 9 should have changed from before
@@ -433,11 +551,18 @@ fn run_respects_groups_to_compress() {
         graphs,
         commit_changes,
         verify,
+        TlsConfig::default(),
+        false,
+        None,
+        "query".to_string(),
+        None,
+        0.0,
+        "ancestors".to_string(),
     )
     .unwrap();
 
     // Run the compressor with those settings
-    run(config);
+    run(config).unwrap();
 
     // This should have created the following structure in the database
     // as it should only compress from groups higher than 2 (non inclusive)
@@ -521,6 +646,13 @@ fn run_is_idempotent_when_run_on_whole_room() {
         graphs,
         commit_changes,
         verify,
+        TlsConfig::default(),
+        false,
+        None,
+        "query".to_string(),
+        None,
+        0.0,
+        "ancestors".to_string(),
     )
     .unwrap();
 
@@ -537,6 +669,13 @@ fn run_is_idempotent_when_run_on_whole_room() {
         graphs,
         commit_changes,
         verify,
+        TlsConfig::default(),
+        false,
+        None,
+        "query".to_string(),
+        None,
+        0.0,
+        "ancestors".to_string(),
     )
     .unwrap();
 
@@ -556,7 +695,7 @@ fn run_is_idempotent_when_run_on_whole_room() {
     let expected = compressed_3_3_from_0_to_13_with_state();
 
     // Run the compressor with those settings for the first time
-    run(config1);
+    run(config1).unwrap();
 
     // Check that the database still gives correct states for each group!
     assert!(database_collapsed_states_match_map(&initial));
@@ -565,7 +704,7 @@ fn run_is_idempotent_when_run_on_whole_room() {
     assert!(database_structure_matches_map(&expected));
 
     // Run the compressor with those settings for the second time
-    run(config2);
+    run(config2).unwrap();
 
     // Check that the database still gives correct states for each group!
     assert!(database_collapsed_states_match_map(&initial));
@@ -573,3 +712,136 @@ fn run_is_idempotent_when_run_on_whole_room() {
     // Check that the structure of the database still matches the expected structure
     assert!(database_structure_matches_map(&expected));
 }
+
+#[test]
+#[serial(db)]
+fn rollback_file_undoes_committed_changes() {
+    setup_logger();
+    // This starts with the following structure
+    //
+    // 0-1-2 3-4-5 6-7-8 9-10-11 12-13
+    //
+    // Each group i has state:
+    //     ('node','is',      i)
+    //     ('group',  j, 'seen') - for all j less than i
+    let initial = line_segments_with_state(0, 13);
+
+    // Place this initial state into an empty database
+    empty_database();
+    add_contents_to_database("room1", &initial);
+
+    // set up the config options
+    let db_url = DB_URL.to_string();
+    let room_id = "room1".to_string();
+    let rollback_path = "./tests/tmp/rollback_file_undoes_committed_changes.sql";
+    let rollback_file = Some(rollback_path.to_string());
+    let min_state_group = None;
+    let min_saved_rows = None;
+    let groups_to_compress = None;
+    let max_state_group = None;
+    let level_sizes = "3,3".to_string();
+    let transactions = true;
+    let graphs = false;
+    let commit_changes = true;
+    let verify = true;
+
+    let config = Config::new(
+        db_url,
+        room_id,
+        None,
+        min_state_group,
+        groups_to_compress,
+        min_saved_rows,
+        max_state_group,
+        level_sizes,
+        transactions,
+        graphs,
+        commit_changes,
+        verify,
+        TlsConfig::default(),
+        false,
+        rollback_file,
+        "query".to_string(),
+        None,
+        0.0,
+        "ancestors".to_string(),
+    )
+    .unwrap();
+
+    // Run the compressor with those settings
+    run(config).unwrap();
+
+    // Groups 6 and 9 should have changed from before
+    let expected = compressed_3_3_from_0_to_13_with_state();
+    assert!(database_structure_matches_map(&expected));
+
+    // Now run the rollback script against the database
+    let rollback_sql = fs::read_to_string(rollback_path).unwrap();
+    let mut client = connect_to_database(DB_URL, &TlsConfig::default()).unwrap();
+    client.batch_execute(&rollback_sql).unwrap();
+
+    // Which should put the database back to its original structure
+    assert!(database_collapsed_states_match_map(&initial));
+    assert!(database_structure_matches_map(&initial));
+}
+
+#[test]
+#[serial(db)]
+fn smallest_delta_changes_commited_and_state_unchanged() {
+    setup_logger();
+    // This starts with the following structure
+    //
+    // 0-1-2 3-4-5 6-7-8 9-10-11 12-13
+    //
+    // Each group i has state:
+    //     ('node','is',      i)
+    //     ('group',  j, 'seen') - for all j less than i
+    let initial = line_segments_with_state(0, 13);
+
+    // Place this initial state into an empty database
+    empty_database();
+    add_contents_to_database("room1", &initial);
+
+    // set up the config options
+    let db_url = DB_URL.to_string();
+    let room_id = "room1".to_string();
+    let min_state_group = None;
+    let min_saved_rows = None;
+    let groups_to_compress = None;
+    let max_state_group = None;
+    let level_sizes = "3,3".to_string();
+    let transactions = true;
+    let graphs = false;
+    let commit_changes = true;
+    let verify = true;
+
+    let config = Config::new(
+        db_url,
+        room_id,
+        None,
+        min_state_group,
+        groups_to_compress,
+        min_saved_rows,
+        max_state_group,
+        level_sizes,
+        transactions,
+        graphs,
+        commit_changes,
+        verify,
+        TlsConfig::default(),
+        true,
+        None,
+        "query".to_string(),
+        None,
+        0.0,
+        "smallest-delta".to_string(),
+    )
+    .unwrap();
+
+    // Run the compressor with those settings
+    run(config).unwrap();
+
+    // The bases may differ from the default strategy, but the database
+    // should still give the correct states for each group
+    assert!(database_collapsed_states_match_map(&initial));
+}
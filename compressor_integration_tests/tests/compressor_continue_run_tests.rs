@@ -5,7 +5,9 @@ use compressor_integration_tests::{
     setup_logger, DB_URL,
 };
 use serial_test::serial;
-use synapse_compress_state::{continue_run, Level};
+use synapse_compress_state::{
+    connect_to_database, continue_run, CompressorError, Level, TlsConfig,
+};
 
 // Tests the saving and continuing functionality
 // The compressor should produce the same results when run in one go
@@ -27,7 +29,8 @@ fn continue_run_called_twice_same_as_run() {
     empty_database();
     add_contents_to_database("room1", &initial);
 
-    let db_url = DB_URL.to_string();
+    // the same connection is used for both runs
+    let mut client = connect_to_database(DB_URL, &TlsConfig::default()).unwrap();
     let room_id = "room1".to_string();
 
     // will run the compression in two batches
@@ -39,7 +42,9 @@ fn continue_run_called_twice_same_as_run() {
     let level_info = vec![Level::new(3), Level::new(3)];
 
     // Run the compressor with those settings
-    let chunk_stats_1 = continue_run(start, chunk_size, &db_url, &room_id, &level_info).unwrap();
+    let chunk_stats_1 = continue_run(start, chunk_size, &mut client, &room_id, &level_info, false)
+        .unwrap()
+        .unwrap();
 
     // Assert that it stopped at 6 (i.e. after the 7 groups 0...6)
     assert_eq!(chunk_stats_1.last_compressed_group, 6);
@@ -58,8 +63,11 @@ fn continue_run_called_twice_same_as_run() {
     let chunk_size = 7;
     let level_info = chunk_stats_1.new_level_info.clone();
 
-    // Run the compressor with those settings
-    let chunk_stats_2 = continue_run(start, chunk_size, &db_url, &room_id, &level_info).unwrap();
+    // Run the compressor with those settings, this time checking the changes
+    // against the database once they have been committed
+    let chunk_stats_2 = continue_run(start, chunk_size, &mut client, &room_id, &level_info, true)
+        .unwrap()
+        .unwrap();
 
     // Assert that it stopped at 7
     assert_eq!(chunk_stats_2.last_compressed_group, 13);
@@ -81,3 +89,61 @@ fn continue_run_called_twice_same_as_run() {
     // Check that the structure of the database matches the expected structure
     assert!(database_structure_matches_map(&expected))
 }
+
+#[test]
+#[serial(db)]
+fn continue_run_verify_committed_reports_groups_that_changed() {
+    setup_logger();
+    // This starts with the following structure
+    //
+    // 0-1-2 3-4-5 6-7-8 9-10-11 12-13
+    //
+    // Each group i has state:
+    //     ('node','is',      i)
+    //     ('group',  j, 'seen') - for all j less than i
+    let initial = line_segments_with_state(0, 13);
+
+    // Place this initial state into an empty database
+    empty_database();
+    add_contents_to_database("room1", &initial);
+
+    let mut client = connect_to_database(DB_URL, &TlsConfig::default()).unwrap();
+
+    // Add a trigger that interferes with the state being written, so that
+    // the state in the database no longer matches what the compressor wrote
+    client
+        .batch_execute(
+            r#"
+            CREATE FUNCTION tamper_with_state() RETURNS TRIGGER AS $$
+            BEGIN
+                NEW.event_id := 'tampered';
+                RETURN NEW;
+            END;
+            $$ LANGUAGE plpgsql;
+
+            CREATE TRIGGER tamper_with_state BEFORE INSERT ON state_groups_state
+                FOR EACH ROW EXECUTE PROCEDURE tamper_with_state();
+            "#,
+        )
+        .unwrap();
+
+    // Compressing the first 7 groups only changes group 6
+    let level_info = vec![Level::new(3), Level::new(3)];
+    let result = continue_run(None, 7, &mut client, "room1", &level_info, true);
+
+    client
+        .batch_execute(
+            r#"
+            DROP TRIGGER tamper_with_state ON state_groups_state;
+            DROP FUNCTION tamper_with_state();
+            "#,
+        )
+        .unwrap();
+
+    match result {
+        Err(CompressorError::CommittedStateMismatch { state_groups }) => {
+            assert_eq!(state_groups, vec![6])
+        }
+        other => panic!("expected a CommittedStateMismatch, got {:?}", other),
+    }
+}
@@ -0,0 +1,115 @@
+use compressor_integration_tests::{
+    add_contents_to_database, database_structure_matches_map, empty_database,
+    map_builder::{line_segments_with_state, line_with_state},
+    setup_logger, DB_URL,
+};
+use serial_test::serial;
+use synapse_compress_state::{
+    connect_to_database, CompressorError, CopyLoader, StateGroupStore, TlsConfig,
+};
+
+#[test]
+#[serial(db)]
+fn copy_loader_gets_same_initial_data_as_query() {
+    setup_logger();
+    // This starts with the following structure
+    //
+    // 0-1-2 3-4-5 6-7-8 9-10-11 12-13
+    //
+    // Each group i has state:
+    //     ('node','is',      i)
+    //     ('group',  j, 'seen') - for all j less than i
+    let initial = line_segments_with_state(0, 13);
+
+    // Place this initial state into an empty database, along with another
+    // room (with an awkward name) that shouldn't be loaded
+    empty_database();
+    add_contents_to_database("room1", &initial);
+    add_contents_to_database("room'2", &line_with_state(14, 20));
+
+    let mut client = connect_to_database(DB_URL, &TlsConfig::default()).unwrap();
+
+    for (min_state_group, max_group_found) in &[(None, 13), (Some(4), 10)] {
+        let from_query = client
+            .get_initial_data("room1", *min_state_group, *max_group_found)
+            .unwrap();
+        let from_copy = CopyLoader(&mut client)
+            .get_initial_data("room1", *min_state_group, *max_group_found)
+            .unwrap();
+
+        assert!(!from_copy.is_empty());
+        assert_eq!(from_query, from_copy);
+    }
+
+    // The room ID is escaped properly when it's put into the COPY
+    let from_query = client.get_initial_data("room'2", None, 20).unwrap();
+    let from_copy = CopyLoader(&mut client)
+        .get_initial_data("room'2", None, 20)
+        .unwrap();
+
+    assert_eq!(from_query.len(), 7);
+    assert_eq!(from_query, from_copy);
+}
+
+#[test]
+#[serial(db)]
+fn send_changes_writes_new_structure_to_database() {
+    setup_logger();
+    // This starts with the following structure
+    //
+    // 0-1-2-3-4-5-...-249
+    //
+    // Each group i has state:
+    //     ('node','is',      i)
+    //     ('group',  j, 'seen') - for all j less than i
+    let initial = line_with_state(0, 249);
+
+    empty_database();
+    add_contents_to_database("room1", &initial);
+
+    // Change enough groups that more than one batch is written:
+    // - every 10th group loses its predecessor
+    // - every odd group gains an entry with values that would need escaping in SQL
+    let mut new_map = initial.clone();
+    for (sg, entry) in new_map.iter_mut() {
+        if sg % 10 == 0 {
+            entry.prev_state_group = None;
+        }
+        if sg % 2 == 1 {
+            entry
+                .state_map
+                .insert("m.room.name", "it's $$ odd", "$event'$$id".into());
+        }
+    }
+
+    let mut client = connect_to_database(DB_URL, &TlsConfig::default()).unwrap();
+    client.send_changes("room1", &initial, &new_map).unwrap();
+
+    assert!(database_structure_matches_map(&new_map));
+}
+
+#[test]
+#[serial(db)]
+fn send_changes_errors_if_group_is_missing_from_new_map() {
+    setup_logger();
+    let initial = line_with_state(0, 13);
+
+    empty_database();
+    add_contents_to_database("room1", &initial);
+
+    // Every group changes, but group 7 was lost from the new map
+    let mut new_map = initial.clone();
+    for entry in new_map.values_mut() {
+        entry.prev_state_group = None;
+    }
+    new_map.remove(&7);
+
+    let mut client = connect_to_database(DB_URL, &TlsConfig::default()).unwrap();
+    assert!(matches!(
+        client.send_changes("room1", &initial, &new_map),
+        Err(CompressorError::MissingStateGroup(7))
+    ));
+
+    // Nothing is written when a group is missing
+    assert!(database_structure_matches_map(&initial));
+}
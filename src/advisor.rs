@@ -0,0 +1,225 @@
+//! Suggests level sizes for a room by trying lots of them.
+//!
+//! Fewer rows in state_groups_state is better, but so is a shorter chain of
+//! predecessors (the sum of the level sizes is the most groups Synapse has to
+//! read to get the state of any group). These pull in different directions,
+//! so rather than picking one winner the advisor reports the candidates that
+//! are Pareto-optimal: the ones where no other candidate is at least as good
+//! at both and better at one.
+
+use indicatif::{ProgressBar, ProgressStyle};
+use log::info;
+use rayon::prelude::*;
+use std::collections::BTreeMap;
+
+use crate::{
+    collapse::DEFAULT_COLLAPSE_CACHE_SIZE,
+    compressor::{BaseSelection, Compressor, CompressorOptions},
+    CompressorError, StateGroupEntry,
+};
+
+/// The level sizes that are tried by default
+pub const DEFAULT_ADVISOR_SIZES: &[usize] = &[10, 25, 50, 100, 200, 500];
+
+/// The most levels that are tried by default
+pub const DEFAULT_ADVISOR_MAX_LEVELS: usize = 3;
+
+/// How well the compressor did with one set of level sizes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Candidate {
+    pub level_sizes: Vec<usize>,
+    /// The number of rows in state_groups_state after compressing
+    pub rows: usize,
+    /// The sum of the level sizes, which is the longest chain of predecessors
+    /// the compressor can create
+    pub max_chain_length: usize,
+    /// How many groups couldn't be made into a delta of a suitable predecessor
+    pub resets_no_suitable_prev: usize,
+}
+
+impl Candidate {
+    /// Whether this candidate is at least as good as `other` on both rows and
+    /// chain length, and better on at least one of them
+    pub fn dominates(&self, other: &Candidate) -> bool {
+        self.rows <= other.rows
+            && self.max_chain_length <= other.max_chain_length
+            && (self.rows < other.rows || self.max_chain_length < other.max_chain_length)
+    }
+}
+
+/// Lists every set of level sizes with between 1 and `max_levels` levels,
+/// where each level is one of `sizes` and no bigger than the level below it
+/// (as in the default of "100,50,25")
+///
+/// # Arguments
+///
+/// * `sizes`       -   The sizes each level can be
+/// * `max_levels`  -   The most levels to use
+pub fn candidate_level_sizes(sizes: &[usize], max_levels: usize) -> Vec<Vec<usize>> {
+    let mut sizes: Vec<usize> = sizes.iter().copied().filter(|s| *s > 0).collect();
+    sizes.sort_unstable_by(|a, b| b.cmp(a));
+    sizes.dedup();
+
+    let mut candidates = Vec::new();
+    let mut current: Vec<Vec<usize>> = vec![Vec::new()];
+
+    for _ in 0..max_levels {
+        current = current
+            .iter()
+            .flat_map(|prefix| {
+                sizes
+                    .iter()
+                    .filter(move |size| match prefix.last() {
+                        Some(last) => *size <= last,
+                        None => true,
+                    })
+                    .map(move |size| {
+                        let mut level_sizes = prefix.clone();
+                        level_sizes.push(*size);
+                        level_sizes
+                    })
+            })
+            .collect();
+        candidates.extend(current.iter().cloned());
+    }
+
+    candidates
+}
+
+/// Runs the compressor on `state_group_map` with each of the sets of level
+/// sizes (in parallel), and returns how well it did with each
+///
+/// # Arguments
+///
+/// * `state_group_map`     -   The state groups to compress
+/// * `level_sizes`         -   The sets of level sizes to try
+/// * `base_selection`      -   How the compressor should pick bases
+pub fn evaluate_level_sizes(
+    state_group_map: &BTreeMap<i64, StateGroupEntry>,
+    level_sizes: &[Vec<usize>],
+    base_selection: BaseSelection,
+) -> Result<Vec<Candidate>, CompressorError> {
+    let pb: ProgressBar;
+    if cfg!(feature = "no-progress-bars") {
+        pb = ProgressBar::hidden();
+    } else {
+        pb = ProgressBar::new(level_sizes.len() as u64);
+    }
+    pb.set_style(
+        ProgressStyle::default_bar().template("[{elapsed_precise}] {bar} {pos}/{len} {msg}"),
+    );
+    pb.set_message("level sizes");
+    pb.enable_steady_tick(100);
+
+    // Each compressor has its own cache, so split the memory budget between
+    // the ones that can be running at the same time
+    let options = CompressorOptions {
+        collapse_cache_size: DEFAULT_COLLAPSE_CACHE_SIZE / rayon::current_num_threads(),
+        base_selection,
+    };
+
+    let candidates = level_sizes
+        .par_iter()
+        .map(|sizes| {
+            let compressor = Compressor::compress(state_group_map, sizes, &options)?;
+
+            pb.inc(1);
+
+            Ok(Candidate {
+                level_sizes: sizes.clone(),
+                rows: compressor
+                    .new_state_group_map
+                    .values()
+                    .map(|entry| entry.state_map.len())
+                    .sum(),
+                max_chain_length: sizes.iter().sum(),
+                resets_no_suitable_prev: compressor.stats.resets_no_suitable_prev,
+            })
+        })
+        .collect::<Result<Vec<_>, CompressorError>>()?;
+
+    pb.finish();
+
+    Ok(candidates)
+}
+
+/// Returns the candidates that no other candidate dominates, in order of
+/// increasing chain length (and so decreasing rows)
+///
+/// Where several candidates have the same rows and chain length, the one with
+/// the fewest levels is kept.
+pub fn pareto_front(candidates: &[Candidate]) -> Vec<&Candidate> {
+    let mut front: Vec<&Candidate> = candidates
+        .iter()
+        .filter(|c| !candidates.iter().any(|other| other.dominates(c)))
+        .collect();
+
+    front.sort_by_key(|c| (c.max_chain_length, c.rows, c.level_sizes.len()));
+    front.dedup_by_key(|c| (c.max_chain_length, c.rows));
+
+    front
+}
+
+/// Tries every candidate set of level sizes on the state groups and logs how
+/// each did, followed by the Pareto-optimal ones as recommendations
+///
+/// # Arguments
+///
+/// * `state_group_map` -   The state groups to compress
+/// * `sizes`           -   The sizes each level can be
+/// * `max_levels`      -   The most levels to use
+/// * `base_selection`  -   How the compressor should pick bases
+pub fn advise(
+    state_group_map: &BTreeMap<i64, StateGroupEntry>,
+    sizes: &[usize],
+    max_levels: usize,
+    base_selection: BaseSelection,
+) -> Result<Vec<Candidate>, CompressorError> {
+    let level_sizes = candidate_level_sizes(sizes, max_levels);
+    if level_sizes.is_empty() {
+        return Err(CompressorError::Config(
+            "There are no level sizes to try".to_string(),
+        ));
+    }
+
+    info!("Trying {} sets of level sizes...", level_sizes.len());
+
+    let mut candidates = evaluate_level_sizes(state_group_map, &level_sizes, base_selection)?;
+    candidates.sort_by_key(|c| (c.rows, c.max_chain_length));
+
+    let original_rows: usize = state_group_map
+        .values()
+        .map(|entry| entry.state_map.len())
+        .sum();
+
+    info!("Original number of rows: {}", original_rows);
+    info!("Results:");
+    log_candidates(candidates.iter(), original_rows);
+
+    let front = pareto_front(&candidates);
+    info!("Recommended level sizes (Pareto-optimal for rows and max chain length):");
+    log_candidates(front.iter().copied(), original_rows);
+
+    Ok(front.into_iter().cloned().collect())
+}
+
+fn log_candidates<'a>(candidates: impl Iterator<Item = &'a Candidate>, original_rows: usize) {
+    info!(
+        "  {:<20} {:>12} {:>8} {:>10} {:>8}",
+        "level sizes", "rows", "%", "max chain", "resets"
+    );
+    for c in candidates {
+        let level_sizes: Vec<String> = c.level_sizes.iter().map(|s| s.to_string()).collect();
+        info!(
+            "  {:<20} {:>12} {:>7.2}% {:>10} {:>8}",
+            level_sizes.join(","),
+            c.rows,
+            (c.rows as f64) / (original_rows.max(1) as f64) * 100.,
+            c.max_chain_length,
+            c.resets_no_suitable_prev
+        );
+    }
+}
+
+#[cfg(test)]
+mod advisor_tests;
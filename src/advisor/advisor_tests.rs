@@ -0,0 +1,130 @@
+use crate::{
+    advisor::{advise, candidate_level_sizes, evaluate_level_sizes, pareto_front, Candidate},
+    compressor::{BaseSelection, Compressor, CompressorOptions},
+    StateGroupEntry,
+};
+use state_map::StateMap;
+use std::collections::BTreeMap;
+use string_cache::DefaultAtom as Atom;
+
+fn candidate(level_sizes: &[usize], rows: usize) -> Candidate {
+    Candidate {
+        level_sizes: level_sizes.to_vec(),
+        rows,
+        max_chain_length: level_sizes.iter().sum(),
+        resets_no_suitable_prev: 0,
+    }
+}
+
+/// A line of 100 groups, each of which adds a new member to the room
+fn line_of_members() -> BTreeMap<i64, StateGroupEntry> {
+    let mut map = BTreeMap::new();
+    let mut prev = None;
+
+    for sg in 0i64..100 {
+        let mut state_map: StateMap<Atom> = StateMap::new();
+        state_map.insert("m.room.member", &format!("@{}:test", sg), "$join".into());
+
+        map.insert(
+            sg,
+            StateGroupEntry {
+                in_range: true,
+                prev_state_group: prev,
+                state_map,
+            },
+        );
+        prev = Some(sg);
+    }
+
+    map
+}
+
+#[test]
+fn candidate_level_sizes_lists_non_increasing_levels() {
+    let candidates = candidate_level_sizes(&[10, 50, 0, 10], 2);
+
+    assert_eq!(
+        candidates,
+        vec![vec![50], vec![10], vec![50, 50], vec![50, 10], vec![10, 10]]
+    );
+}
+
+#[test]
+fn candidate_level_sizes_is_empty_without_levels() {
+    assert!(candidate_level_sizes(&[10, 50], 0).is_empty());
+    assert!(candidate_level_sizes(&[], 3).is_empty());
+}
+
+#[test]
+fn dominates_needs_to_be_strictly_better_at_something() {
+    assert!(candidate(&[10], 5).dominates(&candidate(&[20], 5)));
+    assert!(candidate(&[10], 5).dominates(&candidate(&[10], 6)));
+    assert!(!candidate(&[10], 5).dominates(&candidate(&[10], 5)));
+    assert!(!candidate(&[10], 5).dominates(&candidate(&[20], 4)));
+}
+
+#[test]
+fn pareto_front_keeps_only_undominated_candidates() {
+    let candidates = vec![
+        candidate(&[100], 10),
+        candidate(&[50, 50], 10),
+        candidate(&[30], 20),
+        candidate(&[25, 25], 15),
+        candidate(&[40], 30),
+        candidate(&[10], 50),
+    ];
+
+    let front: Vec<Vec<usize>> = pareto_front(&candidates)
+        .into_iter()
+        .map(|c| c.level_sizes.clone())
+        .collect();
+
+    // [50, 50] has the same rows and chain length as [100] so only the one
+    // with fewer levels is kept, and [40] is beaten by [30]
+    assert_eq!(front, vec![vec![10], vec![30], vec![25, 25], vec![100]]);
+}
+
+#[test]
+fn evaluate_level_sizes_matches_running_the_compressor() {
+    let map = line_of_members();
+    let level_sizes = vec![vec![10], vec![10, 5], vec![3, 3, 3]];
+
+    let candidates = evaluate_level_sizes(&map, &level_sizes, BaseSelection::Ancestors).unwrap();
+
+    for (sizes, candidate) in level_sizes.iter().zip(&candidates) {
+        let compressor = Compressor::compress(&map, sizes, &CompressorOptions::default()).unwrap();
+        let rows: usize = compressor
+            .new_state_group_map
+            .values()
+            .map(|e| e.state_map.len())
+            .sum();
+
+        assert_eq!(
+            candidate,
+            &Candidate {
+                level_sizes: sizes.clone(),
+                rows,
+                max_chain_length: sizes.iter().sum(),
+                resets_no_suitable_prev: compressor.stats.resets_no_suitable_prev,
+            }
+        );
+    }
+}
+
+#[test]
+fn advise_recommends_the_pareto_front() {
+    let map = line_of_members();
+
+    let recommended = advise(&map, &[5, 20], 2, BaseSelection::Ancestors).unwrap();
+
+    let candidates = evaluate_level_sizes(
+        &map,
+        &candidate_level_sizes(&[5, 20], 2),
+        BaseSelection::Ancestors,
+    )
+    .unwrap();
+    let expected: Vec<Candidate> = pareto_front(&candidates).into_iter().cloned().collect();
+
+    assert!(!recommended.is_empty());
+    assert_eq!(recommended, expected);
+}
@@ -0,0 +1,185 @@
+//! A cache of collapsed state groups.
+//!
+//! Working out the full state of a group means following its chain of
+//! predecessors back to a snapshot and combining all of the deltas together.
+//! Both the compressor (when looking for a base
+//! to calculate a delta against) and the verifier do this for most of the
+//! groups in a chunk, so walking the whole chain every time makes them
+//! quadratic in the length of the chains.
+//!
+//! `CollapseCache` instead keeps the collapsed state of recently used groups.
+//! When a group is collapsed, the chain is only followed back as far as the
+//! nearest ancestor that is already in the cache, and only the deltas after
+//! that ancestor are applied on top of it. The cache is bounded by the total
+//! number of state entries it holds, and the least recently used groups are
+//! evicted once that is exceeded.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
+
+use log::trace;
+use state_map::StateMap;
+use string_cache::DefaultAtom as Atom;
+
+use crate::{CompressorError, StateGroupEntry};
+
+/// The default number of state entries (summed over all the cached groups)
+/// that a `CollapseCache` will hold. Each entry is roughly the size of three
+/// interned strings, so this is in the order of tens of megabytes.
+pub const DEFAULT_COLLAPSE_CACHE_SIZE: usize = 2_000_000;
+
+/// A cached collapsed state group
+struct CachedState {
+    state_map: Arc<StateMap<Atom>>,
+    // The value of the cache's clock when this was last used
+    last_used: u64,
+}
+
+/// Collapses the state groups in a map, reusing the collapsed state of
+/// ancestors that have been seen recently
+pub struct CollapseCache<'a> {
+    map: &'a BTreeMap<i64, StateGroupEntry>,
+    cached: HashMap<i64, CachedState>,
+    // The cached groups ordered by when they were last used, oldest first
+    recency: BTreeMap<u64, i64>,
+    clock: u64,
+    // The number of state entries currently held, and the most allowed
+    size: usize,
+    max_size: usize,
+    hits: usize,
+    misses: usize,
+}
+
+impl<'a> CollapseCache<'a> {
+    /// Creates an empty cache for the groups in `map`
+    ///
+    /// # Arguments
+    ///
+    /// * `map`         -   The state groups (with their deltas) to collapse
+    /// * `max_size`    -   The most state entries that the cache will hold
+    ///                     at once (0 disables caching)
+    pub fn new(map: &'a BTreeMap<i64, StateGroupEntry>, max_size: usize) -> CollapseCache<'a> {
+        CollapseCache {
+            map,
+            cached: HashMap::new(),
+            recency: BTreeMap::new(),
+            clock: 0,
+            size: 0,
+            max_size,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Gets the full state for a given group
+    ///
+    /// Returns a `MissingStateGroup` error if the group, or any of the
+    /// predecessors that need to be looked at, are not in the map
+    pub fn collapse(&mut self, state_group: i64) -> Result<Arc<StateMap<Atom>>, CompressorError> {
+        if let Some(state_map) = self.touch(state_group) {
+            self.hits += 1;
+            return Ok(state_map);
+        }
+        self.misses += 1;
+
+        // Walk up the chain until either a cached group or a snapshot is found
+        let mut stack = Vec::new();
+        let mut base = None;
+        let mut current = Some(state_group);
+
+        while let Some(sg) = current {
+            if let Some(state_map) = self.touch(sg) {
+                base = Some(state_map);
+                break;
+            }
+
+            let entry = self
+                .map
+                .get(&sg)
+                .ok_or(CompressorError::MissingStateGroup(sg))?;
+            stack.push(sg);
+            current = entry.prev_state_group;
+        }
+
+        let mut state_map = match base {
+            Some(base) => (*base).clone(),
+            None => StateMap::new(),
+        };
+
+        for sg in stack.iter().rev() {
+            state_map.extend(
+                self.map[sg]
+                    .state_map
+                    .iter()
+                    .map(|((t, s), e)| ((t, s), e.clone())),
+            );
+        }
+
+        let state_map = Arc::new(state_map);
+        self.insert(state_group, state_map.clone());
+
+        Ok(state_map)
+    }
+
+    /// The number of lookups that were answered straight from the cache,
+    /// and the number that weren't
+    pub fn hits_and_misses(&self) -> (usize, usize) {
+        (self.hits, self.misses)
+    }
+
+    /// Looks a group up in the cache, marking it as the most recently used
+    fn touch(&mut self, state_group: i64) -> Option<Arc<StateMap<Atom>>> {
+        let cached = self.cached.get_mut(&state_group)?;
+
+        self.recency.remove(&cached.last_used);
+        self.clock += 1;
+        cached.last_used = self.clock;
+        self.recency.insert(self.clock, state_group);
+
+        Some(cached.state_map.clone())
+    }
+
+    /// Adds a collapsed group to the cache, evicting the least recently used
+    /// groups until it fits in the budget
+    fn insert(&mut self, state_group: i64, state_map: Arc<StateMap<Atom>>) {
+        // Count every group as at least one entry so that lots of empty
+        // groups still can't grow the cache without bound
+        let size = state_map.len().max(1);
+        if size > self.max_size {
+            return;
+        }
+
+        while self.size + size > self.max_size {
+            let oldest = match self.recency.values().next() {
+                Some(&sg) => sg,
+                None => break,
+            };
+            self.evict(oldest);
+        }
+
+        self.clock += 1;
+        self.recency.insert(self.clock, state_group);
+        self.cached.insert(
+            state_group,
+            CachedState {
+                state_map,
+                last_used: self.clock,
+            },
+        );
+        self.size += size;
+    }
+
+    /// Removes a group from the cache
+    fn evict(&mut self, state_group: i64) {
+        if let Some(cached) = self.cached.remove(&state_group) {
+            trace!("Evicting state group {} from collapse cache", state_group);
+            self.recency.remove(&cached.last_used);
+            self.size -= cached.state_map.len().max(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod collapse_tests;
@@ -0,0 +1,147 @@
+use crate::{collapse::CollapseCache, collapse_state_maps, CompressorError, StateGroupEntry};
+use state_map::StateMap;
+use std::collections::BTreeMap;
+
+/// Builds the following structure
+///
+/// 0-1-2-3-4-5-6-7-8-9-10-11-12-13
+///
+/// Where each group i has state:
+///     ('node','is',      i)
+///     ('group',  j, 'seen') - for all j less than i
+fn line_with_state() -> BTreeMap<i64, StateGroupEntry> {
+    let mut initial: BTreeMap<i64, StateGroupEntry> = BTreeMap::new();
+    let mut prev = None;
+
+    for i in 0i64..=13i64 {
+        let mut entry = StateGroupEntry {
+            in_range: true,
+            prev_state_group: prev,
+            state_map: StateMap::new(),
+        };
+        entry
+            .state_map
+            .insert("group", &i.to_string(), "seen".into());
+        entry.state_map.insert("node", "is", i.to_string().into());
+
+        initial.insert(i, entry);
+
+        prev = Some(i)
+    }
+
+    initial
+}
+
+#[test]
+fn collapse_matches_uncached_collapse_for_every_group() {
+    let initial = line_with_state();
+    let mut cache = CollapseCache::new(&initial, 1000);
+
+    // go backwards so that the first lookup has nothing cached to start from
+    for sg in (0..=13).rev() {
+        assert_eq!(
+            *cache.collapse(sg).unwrap(),
+            collapse_state_maps(&initial, sg).unwrap()
+        );
+    }
+}
+
+#[test]
+fn collapse_reuses_cached_groups() {
+    let initial = line_with_state();
+    let mut cache = CollapseCache::new(&initial, 1000);
+
+    cache.collapse(12).unwrap();
+    cache.collapse(13).unwrap();
+    cache.collapse(13).unwrap();
+
+    // Only the second lookup of 13 is answered straight from the cache
+    assert_eq!(cache.hits_and_misses(), (1, 2));
+
+    // 13 is built on top of the cached copy of 12 rather than walking back
+    // to 0, but should still have all of the state
+    let state = cache.collapse(13).unwrap();
+    assert_eq!(state.len(), 15);
+    assert_eq!(state.get("node", "is"), Some(&"13".into()));
+}
+
+#[test]
+fn collapse_stays_within_memory_budget() {
+    let initial = line_with_state();
+    // Group i has i + 2 entries, so only a couple of groups fit at once
+    let mut cache = CollapseCache::new(&initial, 30);
+
+    for sg in 0..=13 {
+        assert_eq!(
+            *cache.collapse(sg).unwrap(),
+            collapse_state_maps(&initial, sg).unwrap()
+        );
+        assert!(cache.size <= 30);
+    }
+
+    // the most recently used groups are the ones that are kept
+    assert!(cache.cached.contains_key(&13));
+    assert!(cache.cached.contains_key(&12));
+    assert!(!cache.cached.contains_key(&0));
+}
+
+#[test]
+fn collapse_evicts_least_recently_used_group() {
+    // Three unrelated snapshots with two entries each
+    let mut initial: BTreeMap<i64, StateGroupEntry> = BTreeMap::new();
+    for i in 0i64..3i64 {
+        let mut entry = StateGroupEntry {
+            in_range: true,
+            prev_state_group: None,
+            state_map: StateMap::new(),
+        };
+        entry.state_map.insert("node", "is", i.to_string().into());
+        entry.state_map.insert("node", "was", i.to_string().into());
+
+        initial.insert(i, entry);
+    }
+
+    // room for two of the groups but not all three
+    let mut cache = CollapseCache::new(&initial, 4);
+
+    cache.collapse(0).unwrap();
+    cache.collapse(1).unwrap();
+
+    // use 0 again so that 1 becomes the least recently used
+    cache.collapse(0).unwrap();
+
+    cache.collapse(2).unwrap();
+
+    assert!(cache.cached.contains_key(&0));
+    assert!(!cache.cached.contains_key(&1));
+    assert!(cache.cached.contains_key(&2));
+    assert_eq!(cache.size, 4);
+}
+
+#[test]
+fn collapse_works_without_any_budget() {
+    let initial = line_with_state();
+    let mut cache = CollapseCache::new(&initial, 0);
+
+    assert_eq!(
+        *cache.collapse(13).unwrap(),
+        collapse_state_maps(&initial, 13).unwrap()
+    );
+    assert!(cache.cached.is_empty());
+}
+
+#[test]
+fn collapse_errors_if_pred_not_in_map() {
+    let mut initial = line_with_state();
+    initial.remove(&5);
+
+    let mut cache = CollapseCache::new(&initial, 1000);
+
+    // groups before the missing one are still fine
+    assert!(cache.collapse(4).is_ok());
+
+    assert!(matches!(
+        cache.collapse(13),
+        Err(CompressorError::MissingStateGroup(5))
+    ));
+}
@@ -29,11 +29,80 @@
 //! ```
 
 use indicatif::{ProgressBar, ProgressStyle};
+use log::debug;
 use state_map::StateMap;
-use std::collections::BTreeMap;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    str::FromStr,
+    sync::Arc,
+};
 use string_cache::DefaultAtom as Atom;
 
-use super::{collapse_state_maps, StateGroupEntry};
+use super::{
+    collapse::{CollapseCache, DEFAULT_COLLAPSE_CACHE_SIZE},
+    CompressorError, StateGroupEntry,
+};
+
+/// How many of the most recently compressed state groups are considered as
+/// bases by `BaseSelection::SmallestDelta`
+const RECENT_GROUPS: usize = 16;
+
+/// The values accepted for the `base_selection` option
+pub const BASE_SELECTIONS: &[&str] = &["ancestors", "smallest-delta"];
+
+/// How the compressor picks a group to base a delta on when the head of the
+/// level a state group is added to isn't a valid base (i.e. it has state that
+/// the group doesn't)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BaseSelection {
+    /// Walk up the new tree from the level head and use the first valid base,
+    /// storing the full state of the group if there isn't one. This keeps the
+    /// chains within the level sizes.
+    Ancestors,
+    /// As well as the first valid ancestor, consider the group's original
+    /// predecessor, the heads of the other levels and the most recently
+    /// compressed groups, and use whichever valid base gives the smallest
+    /// delta. This can cut the number of full states stored, but the chains
+    /// of predecessors are no longer limited by the level sizes.
+    SmallestDelta,
+}
+
+impl FromStr for BaseSelection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ancestors" => Ok(BaseSelection::Ancestors),
+            "smallest-delta" => Ok(BaseSelection::SmallestDelta),
+            _ => Err(format!(
+                "'{}' is not a valid base selection (expected one of: {})",
+                s,
+                BASE_SELECTIONS.join(", ")
+            )),
+        }
+    }
+}
+
+/// Options that change how the compressor runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompressorOptions {
+    /// The most collapsed states to keep in memory at once
+    pub collapse_cache_size: usize,
+    /// How to pick a base when the level head isn't a valid one
+    pub base_selection: BaseSelection,
+}
+
+impl Default for CompressorOptions {
+    fn default() -> Self {
+        CompressorOptions {
+            collapse_cache_size: DEFAULT_COLLAPSE_CACHE_SIZE,
+            base_selection: BaseSelection::Ancestors,
+        }
+    }
+}
+
+/// A group that a delta can be based on, and its full state
+type Base = (i64, Arc<StateMap<Atom>>);
 
 /// Holds information about a particular level.
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -119,41 +188,209 @@ pub struct Stats {
     pub resets_no_suitable_prev_size: usize,
     /// How many state groups we have changed.
     pub state_groups_changed: usize,
+    /// How many state groups were based on a group that walking up the tree
+    /// from the level head wouldn't have found (only with
+    /// `BaseSelection::SmallestDelta`).
+    pub alternative_bases: usize,
+    /// How long the chains of predecessors are in the new tree.
+    pub chain_stats: ChainStats,
+    /// Which state the groups counted by `resets_no_suitable_prev` dropped.
+    pub reset_causes: ResetCauses,
+}
+
+/// Records which state was dropped by the groups that had to be stored in
+/// full, i.e. the (type, state_key) pairs that the head of the group's level
+/// had but the group didn't. A delta can't remove state, so these are what
+/// stopped the head from being used as a base (for example members that were
+/// kicked by state resolution).
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct ResetCauses {
+    /// For each event type, how many resets dropped at least one key of it
+    pub types: HashMap<String, usize>,
+    /// For each (type, state_key), how many resets dropped it
+    pub keys: HashMap<(String, String), usize>,
+}
+
+impl ResetCauses {
+    /// Records a reset of a group with state `state`, which couldn't use a
+    /// group with state `head` as its base
+    pub fn record(&mut self, head: &StateMap<Atom>, state: &StateMap<Atom>) {
+        let mut types = HashSet::new();
+        for (t, s) in head.keys() {
+            if !state.contains_key(t, s) {
+                *self.keys.entry((t.to_string(), s.to_string())).or_default() += 1;
+                types.insert(t);
+            }
+        }
+
+        for t in types {
+            *self.types.entry(t.to_string()).or_default() += 1;
+        }
+    }
+
+    /// The event types and how many resets they caused, most first
+    pub fn types_by_count(&self) -> Vec<(&str, usize)> {
+        let mut types: Vec<(&str, usize)> = self
+            .types
+            .iter()
+            .map(|(t, count)| (t.as_str(), *count))
+            .collect();
+        types.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        types
+    }
+
+    /// The (type, state_key) pairs and how many resets they caused, most
+    /// first
+    pub fn keys_by_count(&self) -> Vec<(&str, &str, usize)> {
+        let mut keys: Vec<(&str, &str, usize)> = self
+            .keys
+            .iter()
+            .map(|((t, s), count)| (t.as_str(), s.as_str(), *count))
+            .collect();
+        keys.sort_by(|a, b| b.2.cmp(&a.2).then((a.0, a.1).cmp(&(b.0, b.1))));
+        keys
+    }
+}
+
+/// Describes how long the chains of predecessors are in a map of state groups,
+/// which is what decides how much work Synapse has to do to read the state of
+/// a group.
+///
+/// Chains are only followed through groups that are in the map.
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub struct ChainStats {
+    /// The number of state groups in the map
+    pub groups: usize,
+    /// The most predecessors any group has
+    pub max_depth: usize,
+    /// The mean number of predecessors of each group
+    pub mean_depth: f64,
+    /// The number of predecessors that 99% of groups have at most
+    pub p99_depth: usize,
+    /// The mean number of rows that have to be read to get the state of a
+    /// group (its own delta and the deltas of all of its predecessors)
+    pub mean_rows_per_lookup: f64,
+}
+
+impl ChainStats {
+    /// Measures the chains in a map of state groups
+    pub fn new(map: &BTreeMap<i64, StateGroupEntry>) -> ChainStats {
+        // The (depth, rows read) of each group, filled in as chains are walked
+        // so that each group only has to be visited once
+        let mut known: HashMap<i64, (usize, usize)> = HashMap::with_capacity(map.len());
+
+        for &sg in map.keys() {
+            // Walk up until a group that has already been measured (or the
+            // start of the chain) and then fill in the groups walked over
+            let mut chain = Vec::new();
+            let mut current = Some(sg);
+            let mut below = None;
+
+            while let Some(group) = current {
+                if let Some(measured) = known.get(&group) {
+                    below = Some(*measured);
+                    break;
+                }
+                let entry = match map.get(&group) {
+                    Some(entry) => entry,
+                    None => break,
+                };
+                chain.push((group, entry.state_map.len()));
+                current = entry.prev_state_group;
+            }
+
+            for (group, size) in chain.into_iter().rev() {
+                let measured = match below {
+                    Some((depth, rows)) => (depth + 1, rows + size),
+                    None => (0, size),
+                };
+                known.insert(group, measured);
+                below = Some(measured);
+            }
+        }
+
+        if known.is_empty() {
+            return ChainStats::default();
+        }
+
+        let mut depths: Vec<usize> = known.values().map(|(depth, _)| *depth).collect();
+        depths.sort_unstable();
+
+        let groups = depths.len();
+        let p99_index = ((groups as f64) * 0.99).ceil() as usize - 1;
+
+        ChainStats {
+            groups,
+            max_depth: depths[groups - 1],
+            mean_depth: depths.iter().sum::<usize>() as f64 / groups as f64,
+            p99_depth: depths[p99_index],
+            mean_rows_per_lookup: known.values().map(|(_, rows)| *rows).sum::<usize>() as f64
+                / groups as f64,
+        }
+    }
+
+    /// Combines the number of rows in a map with how deep its chains are, as
+    /// `rows + depth_weight * (sum of the depths of every group)`, so that
+    /// a lower cost is better
+    ///
+    /// # Arguments
+    ///
+    /// * `rows`            -   The number of rows in state_groups_state
+    /// * `depth_weight`    -   How many rows making every group one hop
+    ///                         deeper is worth
+    pub fn cost(&self, rows: usize, depth_weight: f64) -> f64 {
+        rows as f64 + depth_weight * self.mean_depth * self.groups as f64
+    }
 }
 
 /// Attempts to compress a set of state deltas using the given level sizes.
 pub struct Compressor<'a> {
     original_state_map: &'a BTreeMap<i64, StateGroupEntry>,
+    collapse_cache: CollapseCache<'a>,
     pub new_state_group_map: BTreeMap<i64, StateGroupEntry>,
     levels: Vec<Level>,
     pub stats: Stats,
+    base_selection: BaseSelection,
+    // The most recently compressed groups, oldest first (only kept for
+    // BaseSelection::SmallestDelta)
+    recent_groups: VecDeque<i64>,
 }
 
 impl<'a> Compressor<'a> {
-    /// Creates a compressor and runs the compression algorithm.
+    /// Creates a compressor and runs the compression algorithm with the given
+    /// options.
     pub fn compress(
         original_state_map: &'a BTreeMap<i64, StateGroupEntry>,
         level_sizes: &[usize],
-    ) -> Compressor<'a> {
+        options: &CompressorOptions,
+    ) -> Result<Compressor<'a>, CompressorError> {
         let mut compressor = Compressor {
             original_state_map,
+            collapse_cache: CollapseCache::new(original_state_map, options.collapse_cache_size),
             new_state_group_map: BTreeMap::new(),
             levels: level_sizes.iter().map(|size| Level::new(*size)).collect(),
             stats: Stats::default(),
+            base_selection: options.base_selection,
+            recent_groups: VecDeque::new(),
         };
 
-        compressor.create_new_tree();
+        compressor.create_new_tree()?;
 
-        compressor
+        Ok(compressor)
     }
 
     /// Creates a compressor and runs the compression algorithm.
     /// used when restoring compressor state from a previous run
     /// in which case the levels heads are also known
+    ///
+    /// This always uses `BaseSelection::Ancestors`: the saved levels only
+    /// describe chains that stay within the level sizes, and the recently
+    /// compressed groups that `SmallestDelta` looks at aren't saved between
+    /// runs.
     pub fn compress_from_save(
         original_state_map: &'a BTreeMap<i64, StateGroupEntry>,
         level_info: &[Level],
-    ) -> Compressor<'a> {
+    ) -> Result<Compressor<'a>, CompressorError> {
         let levels = level_info
             .iter()
             .map(|l| Level::restore((*l).max_length, (*l).current_chain_length, (*l).head))
@@ -161,13 +398,16 @@ impl<'a> Compressor<'a> {
 
         let mut compressor = Compressor {
             original_state_map,
+            collapse_cache: CollapseCache::new(original_state_map, DEFAULT_COLLAPSE_CACHE_SIZE),
             new_state_group_map: BTreeMap::new(),
             levels,
             stats: Stats::default(),
+            base_selection: BaseSelection::Ancestors,
+            recent_groups: VecDeque::new(),
         };
 
-        compressor.create_new_tree();
-        compressor
+        compressor.create_new_tree()?;
+        Ok(compressor)
     }
 
     /// Returns all the state required to save the compressor so it can be continued later
@@ -176,7 +416,7 @@ impl<'a> Compressor<'a> {
     }
 
     /// Actually runs the compression algorithm
-    fn create_new_tree(&mut self) {
+    fn create_new_tree(&mut self) -> Result<(), CompressorError> {
         if !self.new_state_group_map.is_empty() {
             panic!("Can only call `create_new_tree` once");
         }
@@ -226,7 +466,7 @@ impl<'a> Compressor<'a> {
                 (entry.state_map.clone(), prev_state_group)
             } else {
                 self.stats.state_groups_changed += 1;
-                self.get_delta(prev_state_group, state_group)
+                self.get_delta(prev_state_group, state_group)?
             };
 
             self.new_state_group_map.insert(
@@ -238,10 +478,28 @@ impl<'a> Compressor<'a> {
                 },
             );
 
+            if self.base_selection == BaseSelection::SmallestDelta {
+                if self.recent_groups.len() == RECENT_GROUPS {
+                    self.recent_groups.pop_front();
+                }
+                self.recent_groups.push_back(state_group);
+            }
+
             pb.inc(1);
         }
 
         pb.finish();
+
+        self.stats.chain_stats = ChainStats::new(&self.new_state_group_map);
+
+        let (hits, misses) = self.collapse_cache.hits_and_misses();
+        debug!(
+            "Collapse cache answered {} of {} lookups",
+            hits,
+            hits + misses
+        );
+
+        Ok(())
     }
 
     /// Attempts to calculate the delta between two state groups.
@@ -249,56 +507,149 @@ impl<'a> Compressor<'a> {
     /// This is not always possible if the given candidate previous state group
     /// have state keys that are not in the new state group. In this case the
     /// function will try and iterate back up the current tree to find a state
-    /// group that can be used as a base for a delta.
+    /// group that can be used as a base for a delta (and, with
+    /// `BaseSelection::SmallestDelta`, try some other groups as well).
     ///
     /// Returns the state map and the actual base state group (if any) used.
-    fn get_delta(&mut self, prev_sg: Option<i64>, sg: i64) -> (StateMap<Atom>, Option<i64>) {
-        let state_map = collapse_state_maps(self.original_state_map, sg);
-
-        let mut prev_sg = if let Some(prev_sg) = prev_sg {
+    fn get_delta(
+        &mut self,
+        prev_sg: Option<i64>,
+        sg: i64,
+    ) -> Result<(StateMap<Atom>, Option<i64>), CompressorError> {
+        let state_map = self.collapse_cache.collapse(sg)?;
+
+        let head = if let Some(prev_sg) = prev_sg {
             prev_sg
         } else {
-            return (state_map, None);
+            return Ok(((*state_map).clone(), None));
         };
 
-        // This is a loop to go through to find the first prev_sg which can be
-        // a valid base for the state group.
-        let mut prev_state_map;
-        'outer: loop {
-            prev_state_map = collapse_state_maps(self.original_state_map, prev_sg);
-            for (t, s) in prev_state_map.keys() {
-                if !state_map.contains_key(t, s) {
-                    // This is not a valid base as it contains key the new state
-                    // group doesn't have. Attempt to walk up the tree to find a
-                    // better base.
-                    if let Some(psg) = self.new_state_group_map[&prev_sg].prev_state_group {
-                        prev_sg = psg;
-                        continue 'outer;
-                    }
-
-                    // Couldn't find a new base, so we give up and just persist
-                    // a full state group here.
-                    self.stats.resets_no_suitable_prev += 1;
-                    self.stats.resets_no_suitable_prev_size += state_map.len();
-
-                    return (state_map, None);
-                }
+        // Go up the tree from the head to find the first group which can be a
+        // valid base for the state group.
+        let mut base = None;
+        let mut candidate = Some(head);
+        while let Some(candidate_sg) = candidate {
+            let candidate_state = self.collapse_cache.collapse(candidate_sg)?;
+            if is_valid_base(&candidate_state, &state_map) {
+                base = Some((candidate_sg, candidate_state));
+                break;
             }
+            candidate = self.new_state_group_map[&candidate_sg].prev_state_group;
+        }
 
-            break;
+        let head_is_valid = matches!(&base, Some((base_sg, _)) if *base_sg == head);
+        if self.base_selection == BaseSelection::SmallestDelta && !head_is_valid {
+            base = self.find_smallest_delta_base(sg, &state_map, base)?;
         }
 
+        let (base_sg, base_state_map) = match base {
+            Some(base) => base,
+            None => {
+                // Couldn't find a base, so we give up and just persist a full
+                // state group here.
+                self.stats.resets_no_suitable_prev += 1;
+                self.stats.resets_no_suitable_prev_size += state_map.len();
+
+                let head_state_map = self.collapse_cache.collapse(head)?;
+                self.stats.reset_causes.record(&head_state_map, &state_map);
+
+                return Ok(((*state_map).clone(), None));
+            }
+        };
+
         // We've found a valid base, now we just need to calculate the delta.
         let mut delta_map = StateMap::new();
 
         for ((t, s), e) in state_map.iter() {
-            if prev_state_map.get(t, s) != Some(e) {
+            if base_state_map.get(t, s) != Some(e) {
                 delta_map.insert(t, s, e.clone());
             }
         }
 
-        (delta_map, Some(prev_sg))
+        Ok((delta_map, Some(base_sg)))
     }
+
+    /// Looks for the valid base that gives the smallest delta for a state
+    /// group, out of the first valid ancestor of the level head (if there
+    /// was one), the group's original predecessor, the heads of the levels
+    /// and the most recently compressed groups
+    ///
+    /// Only groups that are already in the new tree are considered, so that
+    /// no cycles can be created. If several give the same size of delta then
+    /// the ancestor is preferred, followed by the candidates in the order
+    /// listed above.
+    ///
+    /// # Arguments
+    ///
+    /// * `sg`          -   The state group that needs a base
+    /// * `state_map`   -   The full state of `sg`
+    /// * `ancestor`    -   The valid base found by walking up from the level
+    ///                     head, and its full state
+    fn find_smallest_delta_base(
+        &mut self,
+        sg: i64,
+        state_map: &StateMap<Atom>,
+        ancestor: Option<Base>,
+    ) -> Result<Option<Base>, CompressorError> {
+        let candidates: Vec<i64> = self.original_state_map[&sg]
+            .prev_state_group
+            .into_iter()
+            .chain(self.levels.iter().filter_map(|level| level.get_head()))
+            .chain(self.recent_groups.iter().rev().copied())
+            .collect();
+
+        let ancestor_sg = ancestor.as_ref().map(|(base_sg, _)| *base_sg);
+        let mut best = ancestor.map(|(base_sg, base_state_map)| {
+            let size = delta_size(&base_state_map, state_map);
+            (base_sg, base_state_map, size)
+        });
+
+        let mut seen = HashSet::new();
+        for candidate_sg in candidates {
+            if candidate_sg == sg
+                || !self.new_state_group_map.contains_key(&candidate_sg)
+                || !seen.insert(candidate_sg)
+            {
+                continue;
+            }
+
+            let candidate_state = self.collapse_cache.collapse(candidate_sg)?;
+            if !is_valid_base(&candidate_state, state_map) {
+                continue;
+            }
+
+            let size = delta_size(&candidate_state, state_map);
+            let is_better = match &best {
+                Some((_, _, best_size)) => size < *best_size,
+                None => true,
+            };
+            if is_better {
+                best = Some((candidate_sg, candidate_state, size));
+            }
+        }
+
+        let best = best.map(|(base_sg, base_state_map, _)| (base_sg, base_state_map));
+        if best.is_some() && best.as_ref().map(|(base_sg, _)| *base_sg) != ancestor_sg {
+            self.stats.alternative_bases += 1;
+        }
+
+        Ok(best)
+    }
+}
+
+/// Whether a group with state `base` can be used as the base of a delta for a
+/// group with state `state`, which it can if `state` has every key in `base`
+/// (as deltas can't remove state)
+fn is_valid_base(base: &StateMap<Atom>, state: &StateMap<Atom>) -> bool {
+    base.keys().all(|(t, s)| state.contains_key(t, s))
+}
+
+/// The number of entries in a delta from `base` to `state`
+fn delta_size(base: &StateMap<Atom>, state: &StateMap<Atom>) -> usize {
+    state
+        .iter()
+        .filter(|((t, s), e)| base.get(t, s) != Some(*e))
+        .count()
 }
 
 #[cfg(test)]
@@ -1,9 +1,10 @@
 use crate::{
-    compressor::{Compressor, Level, Stats},
+    collapse::{CollapseCache, DEFAULT_COLLAPSE_CACHE_SIZE},
+    compressor::{BaseSelection, Compressor, CompressorOptions, Level, Stats},
     StateGroupEntry,
 };
 use state_map::StateMap;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, VecDeque};
 use string_cache::DefaultAtom as Atom;
 
 #[test]
@@ -27,7 +28,8 @@ fn compress_creates_correct_compressor() {
         prev = Some(i)
     }
 
-    let compressor = Compressor::compress(&initial, &[3, 3]);
+    let compressor =
+        Compressor::compress(&initial, &[3, 3], &CompressorOptions::default()).unwrap();
 
     let new_state = &compressor.new_state_group_map;
 
@@ -115,12 +117,15 @@ fn create_new_tree_does_nothing_if_already_compressed() {
 
     let mut compressor = Compressor {
         original_state_map: &initial,
+        collapse_cache: CollapseCache::new(&initial, DEFAULT_COLLAPSE_CACHE_SIZE),
         new_state_group_map: BTreeMap::new(),
         levels: vec![Level::new(3), Level::new(3)],
         stats: Stats::default(),
+        base_selection: BaseSelection::Ancestors,
+        recent_groups: VecDeque::new(),
     };
 
-    compressor.create_new_tree();
+    compressor.create_new_tree().unwrap();
 
     let new_state = &compressor.new_state_group_map;
 
@@ -150,11 +155,14 @@ fn create_new_tree_respects_levels() {
 
     let mut compressor = Compressor {
         original_state_map: &initial,
+        collapse_cache: CollapseCache::new(&initial, DEFAULT_COLLAPSE_CACHE_SIZE),
         new_state_group_map: BTreeMap::new(),
         levels: vec![Level::new(3), Level::new(3)],
         stats: Stats::default(),
+        base_selection: BaseSelection::Ancestors,
+        recent_groups: VecDeque::new(),
     };
-    compressor.create_new_tree();
+    compressor.create_new_tree().unwrap();
 
     let new_state = &compressor.new_state_group_map;
 
@@ -212,12 +220,15 @@ fn create_new_tree_panics_if_run_twice() {
 
     let mut compressor = Compressor {
         original_state_map: &initial,
+        collapse_cache: CollapseCache::new(&initial, DEFAULT_COLLAPSE_CACHE_SIZE),
         new_state_group_map: BTreeMap::new(),
         levels: vec![Level::new(3), Level::new(3)],
         stats: Stats::default(),
+        base_selection: BaseSelection::Ancestors,
+        recent_groups: VecDeque::new(),
     };
-    compressor.create_new_tree();
-    compressor.create_new_tree();
+    compressor.create_new_tree().unwrap();
+    compressor.create_new_tree().unwrap();
 }
 
 #[test]
@@ -243,11 +254,14 @@ fn create_new_tree_respects_all_not_in_range() {
 
     let mut compressor = Compressor {
         original_state_map: &initial,
+        collapse_cache: CollapseCache::new(&initial, DEFAULT_COLLAPSE_CACHE_SIZE),
         new_state_group_map: BTreeMap::new(),
         levels: vec![Level::new(3), Level::new(3)],
         stats: Stats::default(),
+        base_selection: BaseSelection::Ancestors,
+        recent_groups: VecDeque::new(),
     };
-    compressor.create_new_tree();
+    compressor.create_new_tree().unwrap();
 
     let new_state = &compressor.new_state_group_map;
 
@@ -305,11 +319,14 @@ fn create_new_tree_respects_some_not_in_range() {
 
     let mut compressor = Compressor {
         original_state_map: &initial,
+        collapse_cache: CollapseCache::new(&initial, DEFAULT_COLLAPSE_CACHE_SIZE),
         new_state_group_map: BTreeMap::new(),
         levels: vec![Level::new(3), Level::new(3)],
         stats: Stats::default(),
+        base_selection: BaseSelection::Ancestors,
+        recent_groups: VecDeque::new(),
     };
-    compressor.create_new_tree();
+    compressor.create_new_tree().unwrap();
 
     let new_state = &compressor.new_state_group_map;
 
@@ -388,11 +405,14 @@ fn create_new_tree_deals_with_impossible_preds() {
 
     let mut compressor = Compressor {
         original_state_map: &initial,
+        collapse_cache: CollapseCache::new(&initial, DEFAULT_COLLAPSE_CACHE_SIZE),
         new_state_group_map: BTreeMap::new(),
         levels: vec![Level::new(3), Level::new(3)],
         stats: Stats::default(),
+        base_selection: BaseSelection::Ancestors,
+        recent_groups: VecDeque::new(),
     };
-    compressor.create_new_tree();
+    compressor.create_new_tree().unwrap();
 
     let new_state = &compressor.new_state_group_map;
 
@@ -471,9 +491,10 @@ fn get_delta_returns_snapshot_if_no_prev_given() {
     //        11
     //
     // State contents should be the same as before
-    let mut compressor = Compressor::compress(&initial, &[3, 3]);
+    let mut compressor =
+        Compressor::compress(&initial, &[3, 3], &CompressorOptions::default()).unwrap();
 
-    let (found_delta, found_pred) = compressor.get_delta(None, 6);
+    let (found_delta, found_pred) = compressor.get_delta(None, 6).unwrap();
 
     let mut expected_delta: StateMap<Atom> = StateMap::new();
     expected_delta.insert("node", "is", "6".into());
@@ -526,9 +547,10 @@ fn get_delta_returns_delta_if_original_predecessor() {
     //        11
     //
     // State contents should be the same as before
-    let mut compressor = Compressor::compress(&initial, &[3, 3]);
+    let mut compressor =
+        Compressor::compress(&initial, &[3, 3], &CompressorOptions::default()).unwrap();
 
-    let (found_delta, found_pred) = compressor.get_delta(Some(5), 6);
+    let (found_delta, found_pred) = compressor.get_delta(Some(5), 6).unwrap();
 
     let mut expected_delta: StateMap<Atom> = StateMap::new();
     expected_delta.insert("node", "is", "6".into());
@@ -575,9 +597,10 @@ fn get_delta_returns_delta_if_original_multi_hop_predecessor() {
     //        11
     //
     // State contents should be the same as before
-    let mut compressor = Compressor::compress(&initial, &[3, 3]);
+    let mut compressor =
+        Compressor::compress(&initial, &[3, 3], &CompressorOptions::default()).unwrap();
 
-    let (found_delta, found_pred) = compressor.get_delta(Some(3), 6);
+    let (found_delta, found_pred) = compressor.get_delta(Some(3), 6).unwrap();
 
     let mut expected_delta: StateMap<Atom> = StateMap::new();
     expected_delta.insert("node", "is", "6".into());
@@ -667,9 +690,12 @@ fn get_delta_returns_snapshot_if_no_prev_possible() {
     // build the compressor with this partialy built new map
     let mut compressor = Compressor {
         original_state_map: &initial,
+        collapse_cache: CollapseCache::new(&initial, DEFAULT_COLLAPSE_CACHE_SIZE),
         new_state_group_map: new_map,
         levels: vec![Level::new(3), Level::new(3)],
         stats: Stats::default(),
+        base_selection: BaseSelection::Ancestors,
+        recent_groups: VecDeque::new(),
     };
 
     // make the levels how they would be after 0,1,2,3 added
@@ -685,7 +711,7 @@ fn get_delta_returns_snapshot_if_no_prev_possible() {
     l2.current_chain_length = 1;
 
     // Now try and find delta for 4 with 3 as pred
-    let (found_delta, found_pred) = compressor.get_delta(Some(3), 4);
+    let (found_delta, found_pred) = compressor.get_delta(Some(3), 4).unwrap();
 
     let mut expected_delta: StateMap<Atom> = StateMap::new();
     expected_delta.insert("node", "is", "4".into());
@@ -694,3 +720,96 @@ fn get_delta_returns_snapshot_if_no_prev_possible() {
     assert_eq!(found_delta, expected_delta);
     assert_eq!(found_pred, None);
 }
+
+/// A line of groups 0-9 followed by group 10, which branches off from group 2
+/// (as happens when events are backfilled)
+///
+/// Each group i has state:
+///     ('node','is',      i)
+///     ('group',  j, 'seen') where j is ancestor of i
+fn line_with_branch() -> BTreeMap<i64, StateGroupEntry> {
+    let mut initial: BTreeMap<i64, StateGroupEntry> = BTreeMap::new();
+
+    for i in 0i64..=10i64 {
+        let prev = match i {
+            0 => None,
+            10 => Some(2),
+            _ => Some(i - 1),
+        };
+
+        let mut entry = StateGroupEntry {
+            in_range: true,
+            prev_state_group: prev,
+            state_map: StateMap::new(),
+        };
+        entry
+            .state_map
+            .insert("group", &i.to_string(), "seen".into());
+        entry.state_map.insert("node", "is", i.to_string().into());
+
+        initial.insert(i, entry);
+    }
+
+    initial
+}
+
+#[test]
+fn smallest_delta_uses_original_predecessor_when_head_is_not_valid() {
+    let initial = line_with_branch();
+
+    let options = CompressorOptions {
+        base_selection: BaseSelection::SmallestDelta,
+        ..CompressorOptions::default()
+    };
+    let compressor = Compressor::compress(&initial, &[3, 3], &options).unwrap();
+
+    let mut expected_delta: StateMap<Atom> = StateMap::new();
+    expected_delta.insert("node", "is", "10".into());
+    expected_delta.insert("group", "10", "seen".into());
+
+    let entry = &compressor.new_state_group_map[&10];
+    assert_eq!(entry.prev_state_group, Some(2));
+    assert_eq!(entry.state_map, expected_delta);
+    assert_eq!(compressor.stats.alternative_bases, 1);
+
+    // none of the ancestors of the level head can be used, so the default
+    // strategy stores the full state of group 10
+    let ancestors = Compressor::compress(&initial, &[3, 3], &CompressorOptions::default()).unwrap();
+    let entry = &ancestors.new_state_group_map[&10];
+    assert_eq!(entry.prev_state_group, None);
+    assert_eq!(entry.state_map.len(), 5);
+    assert_eq!(
+        ancestors.stats.resets_no_suitable_prev,
+        compressor.stats.resets_no_suitable_prev + 1
+    );
+}
+
+#[test]
+fn smallest_delta_keeps_the_same_state() {
+    let initial = line_with_branch();
+
+    let options = CompressorOptions {
+        base_selection: BaseSelection::SmallestDelta,
+        ..CompressorOptions::default()
+    };
+    let compressor = Compressor::compress(&initial, &[3, 3], &options).unwrap();
+
+    let mut original = CollapseCache::new(&initial, DEFAULT_COLLAPSE_CACHE_SIZE);
+    let mut new = CollapseCache::new(&compressor.new_state_group_map, DEFAULT_COLLAPSE_CACHE_SIZE);
+    for sg in initial.keys() {
+        assert_eq!(original.collapse(*sg).unwrap(), new.collapse(*sg).unwrap());
+    }
+}
+
+#[test]
+fn base_selection_parses_from_str() {
+    assert_eq!(
+        "ancestors".parse::<BaseSelection>(),
+        Ok(BaseSelection::Ancestors)
+    );
+    assert_eq!(
+        "smallest-delta".parse::<BaseSelection>(),
+        Ok(BaseSelection::SmallestDelta)
+    );
+    assert!("smallest".parse::<BaseSelection>().is_err());
+}
@@ -1,9 +1,13 @@
 use crate::{
-    compressor::{Compressor, Level, Stats},
+    collapse::{CollapseCache, DEFAULT_COLLAPSE_CACHE_SIZE},
+    compressor::{
+        BaseSelection, ChainStats, Compressor, CompressorOptions, Level, ResetCauses, Stats,
+    },
     StateGroupEntry,
 };
 use state_map::StateMap;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, VecDeque};
+use string_cache::DefaultAtom as Atom;
 
 #[test]
 fn stats_correct_when_no_resets() {
@@ -28,9 +32,12 @@ fn stats_correct_when_no_resets() {
 
     let mut compressor = Compressor {
         original_state_map: &initial,
+        collapse_cache: CollapseCache::new(&initial, DEFAULT_COLLAPSE_CACHE_SIZE),
         new_state_group_map: BTreeMap::new(),
         levels: vec![Level::new(3), Level::new(3)],
         stats: Stats::default(),
+        base_selection: BaseSelection::Ancestors,
+        recent_groups: VecDeque::new(),
     };
 
     // This should create the following structure
@@ -40,7 +47,7 @@ fn stats_correct_when_no_resets() {
     // 2  5 7 9
     //      8 10
     //        11
-    compressor.create_new_tree();
+    compressor.create_new_tree().unwrap();
 
     // No resets should have taken place
     assert_eq!(compressor.stats.resets_no_suitable_prev, 0);
@@ -48,6 +55,14 @@ fn stats_correct_when_no_resets() {
 
     // Groups 3,6,9,12 should be the only ones changed
     assert_eq!(compressor.stats.state_groups_changed, 4);
+
+    // The depths are 0,1,2 0,1,2 1,2,3 2,3,4 0,1
+    let chain_stats = compressor.stats.chain_stats;
+    assert_eq!(chain_stats.groups, 14);
+    assert_eq!(chain_stats.max_depth, 4);
+    assert_eq!(chain_stats.p99_depth, 4);
+    assert!((chain_stats.mean_depth - 22.0 / 14.0).abs() < 1e-9);
+    assert_eq!(chain_stats.mean_rows_per_lookup, 0.0);
 }
 
 #[test]
@@ -85,9 +100,12 @@ fn stats_correct_when_some_resets() {
 
     let mut compressor = Compressor {
         original_state_map: &initial,
+        collapse_cache: CollapseCache::new(&initial, DEFAULT_COLLAPSE_CACHE_SIZE),
         new_state_group_map: BTreeMap::new(),
         levels: vec![Level::new(3), Level::new(3)],
         stats: Stats::default(),
+        base_selection: BaseSelection::Ancestors,
+        recent_groups: VecDeque::new(),
     };
 
     // This should create the following structure
@@ -100,7 +118,7 @@ fn stats_correct_when_some_resets() {
     // 2  5  7  9
     //       8  10
     //          11
-    compressor.create_new_tree();
+    compressor.create_new_tree().unwrap();
 
     // the reset required for 4 contributes 2 to the size stat
     // - (1 'node' and 1 'group') entry
@@ -164,9 +182,12 @@ fn stats_correct_if_no_changes() {
 
     let mut compressor = Compressor {
         original_state_map: &initial,
+        collapse_cache: CollapseCache::new(&initial, DEFAULT_COLLAPSE_CACHE_SIZE),
         new_state_group_map: BTreeMap::new(),
         levels: vec![Level::new(3), Level::new(3)],
         stats: Stats::default(),
+        base_selection: BaseSelection::Ancestors,
+        recent_groups: VecDeque::new(),
     };
 
     // This should create the following structure (i.e. no change)
@@ -176,10 +197,165 @@ fn stats_correct_if_no_changes() {
     // 2  5 7 9
     //      8 10
     //        11
-    compressor.create_new_tree();
+    compressor.create_new_tree().unwrap();
 
     // No changes should have been made (the old tree should be the same)
     assert_eq!(compressor.stats.resets_no_suitable_prev, 0);
     assert_eq!(compressor.stats.resets_no_suitable_prev_size, 0);
     assert_eq!(compressor.stats.state_groups_changed, 0);
 }
+
+#[test]
+fn chain_stats_measures_depth_and_rows_read() {
+    let mut map: BTreeMap<i64, StateGroupEntry> = BTreeMap::new();
+
+    // 0-1-2-...-9, each with one row, and 20 which points at the missing
+    // group 19 and has two rows
+    for i in 0i64..=9i64 {
+        let mut state_map = StateMap::new();
+        state_map.insert("group", &i.to_string(), "seen".into());
+        map.insert(
+            i,
+            StateGroupEntry {
+                in_range: true,
+                prev_state_group: if i == 0 { None } else { Some(i - 1) },
+                state_map,
+            },
+        );
+    }
+    let mut state_map = StateMap::new();
+    state_map.insert("group", "20", "seen".into());
+    state_map.insert("group", "19", "seen".into());
+    map.insert(
+        20,
+        StateGroupEntry {
+            in_range: true,
+            prev_state_group: Some(19),
+            state_map,
+        },
+    );
+
+    let chain_stats = ChainStats::new(&map);
+
+    assert_eq!(chain_stats.groups, 11);
+    assert_eq!(chain_stats.max_depth, 9);
+    assert_eq!(chain_stats.p99_depth, 9);
+    // depths are 0..=9 and 0
+    assert!((chain_stats.mean_depth - 45.0 / 11.0).abs() < 1e-9);
+    // rows read are 1..=10 and 2
+    assert!((chain_stats.mean_rows_per_lookup - 57.0 / 11.0).abs() < 1e-9);
+
+    assert!((chain_stats.cost(12, 0.0) - 12.0).abs() < 1e-9);
+    assert!((chain_stats.cost(12, 2.0) - 102.0).abs() < 1e-9);
+}
+
+#[test]
+fn chain_stats_p99_ignores_a_few_long_chains() {
+    let mut map: BTreeMap<i64, StateGroupEntry> = BTreeMap::new();
+
+    // 200 snapshots, and a chain of 3 groups hanging off the last
+    for i in 0i64..203 {
+        map.insert(
+            i,
+            StateGroupEntry {
+                in_range: true,
+                prev_state_group: if i > 200 { Some(i - 1) } else { None },
+                state_map: StateMap::new(),
+            },
+        );
+    }
+
+    let chain_stats = ChainStats::new(&map);
+
+    assert_eq!(chain_stats.max_depth, 2);
+    assert_eq!(chain_stats.p99_depth, 0);
+}
+
+#[test]
+fn chain_stats_of_empty_map_is_zero() {
+    assert_eq!(ChainStats::new(&BTreeMap::new()), ChainStats::default());
+}
+
+#[test]
+fn reset_causes_record_dropped_keys() {
+    let mut head: StateMap<Atom> = StateMap::new();
+    head.insert("m.room.create", "", "$create".into());
+    head.insert("m.room.member", "@alice:test", "$alice".into());
+    head.insert("m.room.member", "@bob:test", "$bob".into());
+    head.insert("m.room.topic", "", "$topic".into());
+
+    let mut state: StateMap<Atom> = StateMap::new();
+    state.insert("m.room.create", "", "$create".into());
+    state.insert("m.room.topic", "", "$topic2".into());
+
+    let mut causes = ResetCauses::default();
+    causes.record(&head, &state);
+
+    state.insert("m.room.member", "@alice:test", "$alice".into());
+    causes.record(&head, &state);
+
+    // changed values aren't counted, and each type is only counted once per
+    // reset
+    assert_eq!(causes.types_by_count(), vec![("m.room.member", 2)]);
+    assert_eq!(
+        causes.keys_by_count(),
+        vec![
+            ("m.room.member", "@bob:test", 2),
+            ("m.room.member", "@alice:test", 1)
+        ]
+    );
+}
+
+#[test]
+fn reset_causes_match_resets() {
+    let mut initial: BTreeMap<i64, StateGroupEntry> = BTreeMap::new();
+    let mut prev = None;
+
+    // This starts with the following structure
+    //
+    // (note missing 3-4 link)
+    // 0-1-2-3
+    // 4-5-6-7-8-9-10-11-12-13
+    //
+    // Each group i has state:
+    //     ('node','is',      i)
+    //     ('group',  j, 'seen') where j is ancestor of i
+    for i in 0i64..=13i64 {
+        // don't add 3-4 link
+        if i == 4 {
+            prev = None
+        }
+
+        let mut entry = StateGroupEntry {
+            in_range: true,
+            prev_state_group: prev,
+            state_map: StateMap::new(),
+        };
+        entry
+            .state_map
+            .insert("group", &i.to_string(), "seen".into());
+        entry.state_map.insert("node", "is", i.to_string().into());
+
+        initial.insert(i, entry);
+
+        prev = Some(i)
+    }
+
+    let compressor =
+        Compressor::compress(&initial, &[3, 3], &CompressorOptions::default()).unwrap();
+    let causes = &compressor.stats.reset_causes;
+
+    // 4 and 6 both have to be stored in full as 3 is the head of their
+    // level, and neither has the groups seen by 3
+    assert_eq!(compressor.stats.resets_no_suitable_prev, 2);
+    assert_eq!(causes.types_by_count(), vec![("group", 2)]);
+    assert_eq!(
+        causes.keys_by_count(),
+        vec![
+            ("group", "0", 2),
+            ("group", "1", 2),
+            ("group", "2", 2),
+            ("group", "3", 2)
+        ]
+    );
+}
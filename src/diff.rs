@@ -0,0 +1,209 @@
+//! Describes how the state of a group differs between two state group maps.
+//!
+//! When verification fails it is much more useful to know which (type,
+//! state_key) pairs diverged, and how each group was built up from its
+//! predecessors, than to see the full state of the group twice. A `StateDiff`
+//! holds the former and a `StateGroupMismatch` adds the latter. Both are
+//! returned as data (inside a `CompressorError`) and their `Display`
+//! implementations give a compact summary for the logs.
+
+use state_map::StateMap;
+use std::{collections::BTreeMap, fmt};
+use string_cache::DefaultAtom as Atom;
+
+use crate::StateGroupEntry;
+
+/// The most differences (or groups in a predecessor chain, or mismatched
+/// groups in an error) that are shown when displaying a mismatch. The full
+/// lists are still kept in the structs.
+pub(crate) const MAX_DISPLAYED: usize = 10;
+
+/// A (type, state_key) pair
+pub type StateKey = (String, String);
+
+/// The differences between the expected and the found state of a group
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StateDiff {
+    /// Entries that should be in the state but are missing
+    pub only_expected: BTreeMap<StateKey, Atom>,
+    /// Entries that are in the state but shouldn't be
+    pub only_found: BTreeMap<StateKey, Atom>,
+    /// Entries that point at the wrong event, as (expected, found)
+    pub different: BTreeMap<StateKey, (Atom, Atom)>,
+}
+
+impl StateDiff {
+    /// Compares two collapsed states
+    ///
+    /// # Arguments
+    ///
+    /// * `expected`    -   The state the group should have
+    /// * `found`       -   The state the group actually has
+    pub fn new(expected: &StateMap<Atom>, found: &StateMap<Atom>) -> StateDiff {
+        let mut diff = StateDiff::default();
+
+        for ((t, s), expected_event) in expected.iter() {
+            let key = (t.to_string(), s.to_string());
+            match found.get(t, s) {
+                None => {
+                    diff.only_expected.insert(key, expected_event.clone());
+                }
+                Some(found_event) if found_event != expected_event => {
+                    diff.different
+                        .insert(key, (expected_event.clone(), found_event.clone()));
+                }
+                Some(_) => {}
+            }
+        }
+
+        for ((t, s), found_event) in found.iter() {
+            if !expected.contains_key(t, s) {
+                diff.only_found
+                    .insert((t.to_string(), s.to_string()), found_event.clone());
+            }
+        }
+
+        diff
+    }
+
+    /// Whether the two states were the same
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The number of (type, state_key) pairs that differ
+    pub fn len(&self) -> usize {
+        self.only_expected.len() + self.only_found.len() + self.different.len()
+    }
+}
+
+impl fmt::Display for StateDiff {
+    /// Writes one line per difference (up to `MAX_DISPLAYED` of them):
+    /// `-` for entries that are missing, `+` for unexpected entries and `~`
+    /// for entries pointing at the wrong event
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let missing = self
+            .only_expected
+            .iter()
+            .map(|((t, s), e)| format!("- ({}, {}) {}", t, s, e));
+        let unexpected = self
+            .only_found
+            .iter()
+            .map(|((t, s), e)| format!("+ ({}, {}) {}", t, s, e));
+        let different = self.different.iter().map(|((t, s), (expected, found))| {
+            format!("~ ({}, {}) expected {}, found {}", t, s, expected, found)
+        });
+
+        let mut lines = missing.chain(unexpected).chain(different);
+        for (i, line) in lines.by_ref().take(MAX_DISPLAYED).enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", line)?;
+        }
+
+        let remaining = lines.count();
+        if remaining > 0 {
+            write!(f, "\n... and {} more", remaining)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A state group whose state doesn't match what it should be
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StateGroupMismatch {
+    /// The group that doesn't match
+    pub state_group: i64,
+    /// How the state of the group differs
+    pub diff: StateDiff,
+    /// The group followed by its predecessors in the map that had the
+    /// expected state
+    pub expected_chain: Vec<i64>,
+    /// The group followed by its predecessors in the map that was checked
+    pub found_chain: Vec<i64>,
+}
+
+impl StateGroupMismatch {
+    /// Compares the state of a group between two maps
+    ///
+    /// Returns None if the states match
+    ///
+    /// # Arguments
+    ///
+    /// * `state_group`     -   The group being compared
+    /// * `expected`        -   The collapsed state from `expected_map`
+    /// * `found`           -   The collapsed state being checked
+    /// * `expected_map`    -   The map the expected state came from
+    /// * `found_map`       -   The map whose structure produced `found`
+    pub fn compare(
+        state_group: i64,
+        expected: &StateMap<Atom>,
+        found: &StateMap<Atom>,
+        expected_map: &BTreeMap<i64, StateGroupEntry>,
+        found_map: &BTreeMap<i64, StateGroupEntry>,
+    ) -> Option<StateGroupMismatch> {
+        if expected == found {
+            return None;
+        }
+
+        Some(StateGroupMismatch {
+            state_group,
+            diff: StateDiff::new(expected, found),
+            expected_chain: predecessor_chain(expected_map, state_group),
+            found_chain: predecessor_chain(found_map, state_group),
+        })
+    }
+}
+
+impl fmt::Display for StateGroupMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "State group {} has {} differences",
+            self.state_group,
+            self.diff.len()
+        )?;
+        writeln!(f, "expected chain: {}", display_chain(&self.expected_chain))?;
+        writeln!(f, "found chain:    {}", display_chain(&self.found_chain))?;
+        write!(f, "{}", self.diff)
+    }
+}
+
+/// Returns the group followed by each of its predecessors in turn
+///
+/// The chain stops early if a predecessor isn't in the map
+pub fn predecessor_chain(map: &BTreeMap<i64, StateGroupEntry>, state_group: i64) -> Vec<i64> {
+    let mut chain = vec![state_group];
+    let mut current = map.get(&state_group).and_then(|e| e.prev_state_group);
+
+    while let Some(sg) = current {
+        chain.push(sg);
+        current = map.get(&sg).and_then(|e| e.prev_state_group);
+    }
+
+    chain
+}
+
+/// Formats a predecessor chain as `6 -> 5 -> 3`, leaving out the middle of
+/// long chains
+fn display_chain(chain: &[i64]) -> String {
+    let groups: Vec<String> = chain.iter().map(|sg| sg.to_string()).collect();
+
+    if groups.len() <= MAX_DISPLAYED {
+        return groups.join(" -> ");
+    }
+
+    let start = &groups[..MAX_DISPLAYED - 1];
+    let last = &groups[groups.len() - 1];
+    format!(
+        "{} -> ... ({} more) -> {}",
+        start.join(" -> "),
+        groups.len() - MAX_DISPLAYED,
+        last
+    )
+}
+
+#[cfg(test)]
+mod diff_tests;
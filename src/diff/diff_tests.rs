@@ -0,0 +1,148 @@
+use crate::{
+    diff::{predecessor_chain, StateDiff, StateGroupMismatch},
+    StateGroupEntry,
+};
+use state_map::StateMap;
+use std::collections::BTreeMap;
+use string_cache::DefaultAtom as Atom;
+
+fn key(t: &str, s: &str) -> (String, String) {
+    (t.to_string(), s.to_string())
+}
+
+#[test]
+fn new_diff_is_empty_for_same_states() {
+    let mut state: StateMap<Atom> = StateMap::new();
+    state.insert("m.room.name", "", "$name".into());
+    state.insert("m.room.member", "@alice:test", "$alice".into());
+
+    let diff = StateDiff::new(&state, &state.clone());
+
+    assert!(diff.is_empty());
+    assert_eq!(diff.len(), 0);
+}
+
+#[test]
+fn new_diff_sorts_differences_into_kinds() {
+    let mut expected: StateMap<Atom> = StateMap::new();
+    expected.insert("m.room.name", "", "$name".into());
+    expected.insert("m.room.member", "@alice:test", "$alice".into());
+    expected.insert("m.room.member", "@bob:test", "$bob".into());
+
+    let mut found: StateMap<Atom> = StateMap::new();
+    found.insert("m.room.name", "", "$name".into());
+    found.insert("m.room.member", "@alice:test", "$alice2".into());
+    found.insert("m.room.topic", "", "$topic".into());
+
+    let diff = StateDiff::new(&expected, &found);
+
+    assert_eq!(diff.len(), 3);
+
+    let mut only_expected = BTreeMap::new();
+    only_expected.insert(key("m.room.member", "@bob:test"), "$bob".into());
+    assert_eq!(diff.only_expected, only_expected);
+
+    let mut only_found = BTreeMap::new();
+    only_found.insert(key("m.room.topic", ""), "$topic".into());
+    assert_eq!(diff.only_found, only_found);
+
+    let mut different = BTreeMap::new();
+    different.insert(
+        key("m.room.member", "@alice:test"),
+        ("$alice".into(), "$alice2".into()),
+    );
+    assert_eq!(diff.different, different);
+
+    assert_eq!(
+        diff.to_string(),
+        "- (m.room.member, @bob:test) $bob\n\
+         + (m.room.topic, ) $topic\n\
+         ~ (m.room.member, @alice:test) expected $alice, found $alice2"
+    );
+}
+
+#[test]
+fn diff_display_only_shows_first_few_differences() {
+    let mut expected: StateMap<Atom> = StateMap::new();
+    for i in 0..25 {
+        expected.insert("m.room.member", &format!("@{}:test", i), "$ev".into());
+    }
+
+    let diff = StateDiff::new(&expected, &StateMap::new());
+    let displayed = diff.to_string();
+
+    assert_eq!(diff.len(), 25);
+    assert_eq!(displayed.lines().count(), 11);
+    assert_eq!(displayed.lines().last(), Some("... and 15 more"));
+}
+
+#[test]
+fn predecessor_chain_follows_map_until_snapshot_or_missing_group() {
+    let mut map: BTreeMap<i64, StateGroupEntry> = BTreeMap::new();
+    // 0-1-2 3-4 and 6 which points at the missing group 5
+    for (sg, prev) in &[
+        (0, None),
+        (1, Some(0)),
+        (2, Some(1)),
+        (3, None),
+        (4, Some(3)),
+        (6, Some(5)),
+    ] {
+        map.insert(
+            *sg,
+            StateGroupEntry {
+                in_range: true,
+                prev_state_group: *prev,
+                state_map: StateMap::new(),
+            },
+        );
+    }
+
+    assert_eq!(predecessor_chain(&map, 2), vec![2, 1, 0]);
+    assert_eq!(predecessor_chain(&map, 3), vec![3]);
+    assert_eq!(predecessor_chain(&map, 6), vec![6, 5]);
+}
+
+#[test]
+fn compare_returns_none_if_states_match() {
+    let mut state: StateMap<Atom> = StateMap::new();
+    state.insert("m.room.name", "", "$name".into());
+
+    let map = BTreeMap::new();
+
+    assert_eq!(
+        StateGroupMismatch::compare(1, &state, &state.clone(), &map, &map),
+        None
+    );
+}
+
+#[test]
+fn mismatch_display_includes_chains_and_diff() {
+    let mut expected: StateMap<Atom> = StateMap::new();
+    expected.insert("m.room.name", "", "$name".into());
+
+    let mut old_map: BTreeMap<i64, StateGroupEntry> = BTreeMap::new();
+    let mut new_map: BTreeMap<i64, StateGroupEntry> = BTreeMap::new();
+    // the old map is a line 0-1-...-11, the new map has 11 point at 0
+    for sg in 0..12 {
+        let entry = StateGroupEntry {
+            in_range: true,
+            prev_state_group: if sg == 0 { None } else { Some(sg - 1) },
+            state_map: StateMap::new(),
+        };
+        old_map.insert(sg, entry.clone());
+        new_map.insert(sg, entry);
+    }
+    new_map.get_mut(&11).unwrap().prev_state_group = Some(0);
+
+    let mismatch =
+        StateGroupMismatch::compare(11, &expected, &StateMap::new(), &old_map, &new_map).unwrap();
+
+    assert_eq!(
+        mismatch.to_string(),
+        "State group 11 has 1 differences\n\
+         expected chain: 11 -> 10 -> 9 -> 8 -> 7 -> 6 -> 5 -> 4 -> 3 -> ... (2 more) -> 0\n\
+         found chain:    11 -> 0\n\
+         - (m.room.name, ) $name"
+    );
+}
@@ -0,0 +1,459 @@
+//! Loads state groups from exports of the database, so that the compressor
+//! can be run without a connection to Postgres.
+//!
+//! Each of the state_groups, state_groups_state and state_group_edges tables
+//! is read from its own file. The files can either be CSV (as written by
+//! `COPY table TO 'file' WITH CSV`, optionally with a header) or in the text
+//! format of `COPY table TO STDOUT` (which is also what `pg_dump -t table`
+//! writes).
+//!
+//! If a file has a header (a CSV header row, or the `COPY table (columns)
+//! FROM stdin;` line written by `pg_dump`) then the columns are found by name.
+//! Otherwise they are assumed to be in the order that Synapse creates them.
+
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufRead, BufReader, Lines},
+    str::FromStr,
+};
+
+use log::info;
+
+use crate::{store::MemoryStore, CompressorError, StateGroupEntry};
+
+/// The values accepted for the `dump_format` option
+pub const DUMP_FORMATS: &[&str] = &["csv", "copy"];
+
+/// The format of the files that the tables were exported to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DumpFormat {
+    /// Comma separated values, with NULL written as an unquoted empty field
+    Csv,
+    /// The tab separated text format used by `COPY` and `pg_dump`
+    Copy,
+}
+
+impl FromStr for DumpFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(DumpFormat::Csv),
+            "copy" => Ok(DumpFormat::Copy),
+            _ => Err(format!(
+                "'{}' is not a valid dump format (expected one of: {})",
+                s,
+                DUMP_FORMATS.join(", ")
+            )),
+        }
+    }
+}
+
+/// The files that each of the tables were exported to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DumpFiles {
+    pub format: DumpFormat,
+    pub state_groups: String,
+    pub state_groups_state: String,
+    pub state_group_edges: String,
+}
+
+/// A single row of a dump, with None for NULL fields
+type Row = Vec<Option<String>>;
+
+/// The columns needed from each table, along with where they are in the
+/// table Synapse creates (used when a file has no header)
+const STATE_GROUPS_COLUMNS: &[(&str, usize)] = &[("id", 0), ("room_id", 1)];
+const STATE_GROUP_EDGES_COLUMNS: &[(&str, usize)] = &[("state_group", 0), ("prev_state_group", 1)];
+const STATE_GROUPS_STATE_COLUMNS: &[(&str, usize)] = &[
+    ("state_group", 0),
+    ("type", 2),
+    ("state_key", 3),
+    ("event_id", 4),
+];
+
+/// Loads the state groups of a room from exported tables into a MemoryStore
+///
+/// The store can then be passed to `run_with_store` in place of a connection
+/// to the database. Only the rows belonging to `room_id` are kept, so the
+/// exports can contain every room.
+///
+/// # Arguments
+///
+/// * `files`   -   Where the tables were exported to, and in what format
+/// * `room_id` -   The room whose state groups should be loaded
+pub fn load_dumps(files: &DumpFiles, room_id: &str) -> Result<MemoryStore, CompressorError> {
+    let mut state_group_map: BTreeMap<i64, StateGroupEntry> = BTreeMap::new();
+
+    info!("Reading state groups from {}...", files.state_groups);
+    for_each_row(
+        &files.state_groups,
+        files.format,
+        STATE_GROUPS_COLUMNS,
+        |position, row| {
+            if row[1].as_deref() == Some(room_id) {
+                let sg = position.parse_id(&row[0])?;
+                state_group_map.insert(sg, StateGroupEntry::default());
+            }
+            Ok(())
+        },
+    )?;
+
+    info!(
+        "Reading state group edges from {}...",
+        files.state_group_edges
+    );
+    for_each_row(
+        &files.state_group_edges,
+        files.format,
+        STATE_GROUP_EDGES_COLUMNS,
+        |position, row| {
+            let sg = position.parse_id(&row[0])?;
+            if let Some(entry) = state_group_map.get_mut(&sg) {
+                entry.prev_state_group = Some(position.parse_id(&row[1])?);
+            }
+            Ok(())
+        },
+    )?;
+
+    info!("Reading state from {}...", files.state_groups_state);
+    for_each_row(
+        &files.state_groups_state,
+        files.format,
+        STATE_GROUPS_STATE_COLUMNS,
+        |position, row| {
+            let sg = position.parse_id(&row[0])?;
+            if let Some(entry) = state_group_map.get_mut(&sg) {
+                match (&row[1], &row[2], &row[3]) {
+                    (Some(t), Some(s), Some(e)) => entry.state_map.insert(t, s, e.as_str().into()),
+                    _ => return Err(position.invalid("type, state_key and event_id can't be NULL")),
+                }
+            }
+            Ok(())
+        },
+    )?;
+
+    info!(
+        "Read {} state groups for room {}",
+        state_group_map.len(),
+        room_id
+    );
+
+    let mut store = MemoryStore::new();
+    store.add_room(room_id, &state_group_map);
+
+    Ok(store)
+}
+
+/// Where in a dump file a row was read from, for error messages
+struct Position<'a> {
+    path: &'a str,
+    line: usize,
+}
+
+impl Position<'_> {
+    fn invalid(&self, reason: &str) -> CompressorError {
+        CompressorError::InvalidDump {
+            file: self.path.to_string(),
+            line: self.line,
+            reason: reason.to_string(),
+        }
+    }
+
+    fn parse_id(&self, field: &Option<String>) -> Result<i64, CompressorError> {
+        field
+            .as_deref()
+            .and_then(|f| f.parse().ok())
+            .ok_or_else(|| self.invalid("state group ids must be integers"))
+    }
+}
+
+/// Reads every row of a dump file, passing the requested columns of each
+/// (in the order they were requested) to `f`
+///
+/// # Arguments
+///
+/// * `path`    -   The file to read
+/// * `format`  -   The format the file is in
+/// * `columns` -   The names of the columns to pass to `f`, and where each
+///                 is if the file has no header
+/// * `f`       -   Called with each row
+fn for_each_row<F>(
+    path: &str,
+    format: DumpFormat,
+    columns: &[(&str, usize)],
+    mut f: F,
+) -> Result<(), CompressorError>
+where
+    F: FnMut(&Position, &[Option<String>]) -> Result<(), CompressorError>,
+{
+    let mut reader = DumpReader {
+        lines: BufReader::new(File::open(path)?).lines(),
+        path,
+        format,
+        line: 0,
+        seen_copy_header: false,
+    };
+
+    let mut indices: Vec<usize> = columns.iter().map(|(_, i)| *i).collect();
+    let mut seen_row = false;
+
+    while let Some(record) = reader.next_record()? {
+        let position = Position {
+            path,
+            line: reader.line,
+        };
+
+        let row = match record {
+            Record::Header(names) => {
+                indices = column_indices(&position, &names, columns)?;
+                continue;
+            }
+            Record::Row(row) => row,
+        };
+
+        // The first column of every table is a state group id, so a first
+        // row that doesn't start with an integer must be a CSV header
+        let is_header = matches!(&row[0], Some(f) if f.parse::<i64>().is_err());
+        if !seen_row && format == DumpFormat::Csv && is_header {
+            let names: Vec<String> = row.into_iter().map(Option::unwrap_or_default).collect();
+            indices = column_indices(&position, &names, columns)?;
+            continue;
+        }
+        seen_row = true;
+
+        let selected: Option<Row> = indices.iter().map(|i| row.get(*i).cloned()).collect();
+        let selected = selected
+            .ok_or_else(|| position.invalid(&format!("only found {} columns", row.len())))?;
+
+        f(&position, &selected)?;
+    }
+
+    Ok(())
+}
+
+/// Finds where each of the requested columns is in a header
+fn column_indices(
+    position: &Position,
+    names: &[String],
+    columns: &[(&str, usize)],
+) -> Result<Vec<usize>, CompressorError> {
+    columns
+        .iter()
+        .map(|(column, _)| {
+            names
+                .iter()
+                .position(|name| name.trim() == *column)
+                .ok_or_else(|| position.invalid(&format!("no {} column in the header", column)))
+        })
+        .collect()
+}
+
+/// Something read from a dump file
+enum Record {
+    /// The names of the columns
+    Header(Vec<String>),
+    /// The fields of a row of the table
+    Row(Row),
+}
+
+/// Splits a dump file into records
+struct DumpReader<'a, B: BufRead> {
+    lines: Lines<B>,
+    path: &'a str,
+    format: DumpFormat,
+    // The number of the line last read (starting from 1)
+    line: usize,
+    // Whether a `COPY ... FROM stdin;` line has been read
+    seen_copy_header: bool,
+}
+
+impl<B: BufRead> DumpReader<'_, B> {
+    fn next_line(&mut self) -> Result<Option<String>, CompressorError> {
+        match self.lines.next() {
+            Some(line) => {
+                self.line += 1;
+                Ok(Some(line?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn invalid(&self, reason: &str) -> CompressorError {
+        Position {
+            path: self.path,
+            line: self.line,
+        }
+        .invalid(reason)
+    }
+
+    /// Reads the next header or row, or returns None at the end of the data
+    fn next_record(&mut self) -> Result<Option<Record>, CompressorError> {
+        while let Some(line) = self.next_line()? {
+            if line.is_empty() {
+                continue;
+            }
+
+            match self.format {
+                DumpFormat::Csv => return self.parse_csv(line).map(|row| Some(Record::Row(row))),
+                DumpFormat::Copy => {
+                    if line == "\\." {
+                        return Ok(None);
+                    }
+                    if !self.seen_copy_header && line.starts_with("COPY ") {
+                        self.seen_copy_header = true;
+                        return Ok(Some(Record::Header(parse_copy_header(&line))));
+                    }
+                    // pg_dump puts comments and SET and SELECT statements
+                    // before the data. Without a header the first column is
+                    // a state group id, so these are the only lines that
+                    // don't start with a digit.
+                    if !self.seen_copy_header && !line.starts_with(|c: char| c.is_ascii_digit()) {
+                        continue;
+                    }
+                    let row = parse_copy_line(&line).map_err(|e| self.invalid(e))?;
+                    return Ok(Some(Record::Row(row)));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Parses a CSV record starting with `line`, reading more lines if a
+    /// quoted field contains a newline
+    fn parse_csv(&mut self, mut line: String) -> Result<Row, CompressorError> {
+        let mut row = Vec::new();
+        let mut field = String::new();
+        // Whether the current field was quoted, which is how CSV tells an
+        // empty string apart from NULL
+        let mut quoted = false;
+        let mut in_quotes = false;
+
+        loop {
+            let mut chars = line.chars().peekable();
+            while let Some(c) = chars.next() {
+                match (c, in_quotes) {
+                    ('"', true) if chars.peek() == Some(&'"') => {
+                        chars.next();
+                        field.push('"');
+                    }
+                    ('"', true) => in_quotes = false,
+                    ('"', false) => {
+                        in_quotes = true;
+                        quoted = true;
+                    }
+                    (',', false) => {
+                        row.push(csv_field(std::mem::take(&mut field), quoted));
+                        quoted = false;
+                    }
+                    (c, _) => field.push(c),
+                }
+            }
+
+            if !in_quotes {
+                break;
+            }
+
+            // The quoted field continues on the next line
+            field.push('\n');
+            line = match self.next_line()? {
+                Some(line) => line,
+                None => return Err(self.invalid("unterminated quoted field")),
+            };
+        }
+
+        row.push(csv_field(field, quoted));
+        Ok(row)
+    }
+}
+
+fn csv_field(field: String, quoted: bool) -> Option<String> {
+    if field.is_empty() && !quoted {
+        None
+    } else {
+        Some(field)
+    }
+}
+
+/// Gets the column names from a `COPY table (a, b, c) FROM stdin;` line
+fn parse_copy_header(line: &str) -> Vec<String> {
+    let start = line.find('(').map_or(0, |i| i + 1);
+    let end = line.rfind(')').unwrap_or(line.len()).max(start);
+
+    line[start..end]
+        .split(',')
+        .map(|name| name.trim().trim_matches('"').to_string())
+        .collect()
+}
+
+/// Parses a line of COPY text, where fields are separated by tabs, `\N` is
+/// NULL and special characters are escaped with backslashes
+fn parse_copy_line(line: &str) -> Result<Row, &'static str> {
+    line.split('\t')
+        .map(|field| match field {
+            "\\N" => Ok(None),
+            _ => unescape_copy_field(field).map(Some),
+        })
+        .collect()
+}
+
+/// Undoes the backslash escapes used by COPY text
+fn unescape_copy_field(field: &str) -> Result<String, &'static str> {
+    if !field.contains('\\') {
+        return Ok(field.to_string());
+    }
+
+    let mut bytes = Vec::with_capacity(field.len());
+    let mut input = field.bytes().peekable();
+
+    while let Some(b) = input.next() {
+        if b != b'\\' {
+            bytes.push(b);
+            continue;
+        }
+
+        match input.next() {
+            Some(b'b') => bytes.push(8),
+            Some(b'f') => bytes.push(12),
+            Some(b'n') => bytes.push(b'\n'),
+            Some(b'r') => bytes.push(b'\r'),
+            Some(b't') => bytes.push(b'\t'),
+            Some(b'v') => bytes.push(11),
+            Some(b'x') => {
+                // Up to two hex digits
+                let mut value = 0;
+                let mut digits = 0;
+                while digits < 2 {
+                    match input.peek().and_then(|d| (*d as char).to_digit(16)) {
+                        Some(d) => value = value * 16 + d as u8,
+                        None => break,
+                    }
+                    input.next();
+                    digits += 1;
+                }
+                bytes.push(if digits == 0 { b'x' } else { value });
+            }
+            Some(d @ b'0'..=b'7') => {
+                // Up to three octal digits
+                let mut value = d - b'0';
+                for _ in 0..2 {
+                    match input.peek() {
+                        Some(d @ b'0'..=b'7') => value = value.wrapping_mul(8) + (d - b'0'),
+                        _ => break,
+                    }
+                    input.next();
+                }
+                bytes.push(value);
+            }
+            Some(other) => bytes.push(other),
+            None => return Err("line ends with a backslash"),
+        }
+    }
+
+    String::from_utf8(bytes).map_err(|_| "field is not valid UTF-8")
+}
+
+#[cfg(test)]
+mod dump_tests;
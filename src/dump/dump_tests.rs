@@ -0,0 +1,195 @@
+use crate::{
+    dump::{load_dumps, parse_copy_line, unescape_copy_field, DumpFiles, DumpFormat},
+    CompressorError, StateGroupEntry,
+};
+use state_map::StateMap;
+use std::{collections::BTreeMap, fs, path::PathBuf};
+use string_cache::DefaultAtom as Atom;
+
+/// Writes the three tables to files in the temp directory, named after the
+/// test so that tests running at the same time don't clash
+fn write_dumps(
+    test: &str,
+    format: DumpFormat,
+    state_groups: &str,
+    state_groups_state: &str,
+    state_group_edges: &str,
+) -> DumpFiles {
+    let write = |table: &str, contents: &str| {
+        let path: PathBuf = std::env::temp_dir().join(format!(
+            "synapse_compress_state_{}_{}_{}",
+            std::process::id(),
+            test,
+            table
+        ));
+        fs::write(&path, contents).unwrap();
+        path.to_str().unwrap().to_string()
+    };
+
+    DumpFiles {
+        format,
+        state_groups: write("state_groups", state_groups),
+        state_groups_state: write("state_groups_state", state_groups_state),
+        state_group_edges: write("state_group_edges", state_group_edges),
+    }
+}
+
+fn remove_dumps(files: &DumpFiles) {
+    for path in &[
+        &files.state_groups,
+        &files.state_groups_state,
+        &files.state_group_edges,
+    ] {
+        let _ = fs::remove_file(path);
+    }
+}
+
+/// The map that the example dumps in these tests should be loaded into
+fn expected_map() -> BTreeMap<i64, StateGroupEntry> {
+    let mut expected = BTreeMap::new();
+
+    let mut state_map: StateMap<Atom> = StateMap::new();
+    state_map.insert("m.room.create", "", "$create".into());
+    state_map.insert("m.room.topic", "", "a \"quoted\",\nmultiline topic".into());
+    expected.insert(
+        1,
+        StateGroupEntry {
+            in_range: false,
+            prev_state_group: None,
+            state_map,
+        },
+    );
+
+    let mut state_map: StateMap<Atom> = StateMap::new();
+    state_map.insert("m.room.member", "@alice:test", "$alice\tjoin".into());
+    expected.insert(
+        3,
+        StateGroupEntry {
+            in_range: false,
+            prev_state_group: Some(1),
+            state_map,
+        },
+    );
+
+    expected
+}
+
+#[test]
+fn load_dumps_reads_csv_with_header() {
+    let files = write_dumps(
+        "csv_header",
+        DumpFormat::Csv,
+        "id,room_id,event_id\n\
+         1,!room:test,$create\n\
+         2,!other:test,$other\n\
+         3,!room:test,$alice\n",
+        "state_group,room_id,type,state_key,event_id\n\
+         1,!room:test,m.room.create,\"\",$create\n\
+         1,!room:test,m.room.topic,\"\",\"a \"\"quoted\"\",\n\
+         multiline topic\"\n\
+         2,!other:test,m.room.create,\"\",$other\n\
+         3,!room:test,m.room.member,@alice:test,\"$alice\tjoin\"\n",
+        "state_group,prev_state_group\n\
+         2,1\n\
+         3,1\n",
+    );
+
+    let store = load_dumps(&files, "!room:test");
+    remove_dumps(&files);
+
+    assert_eq!(store.unwrap().state_groups(), &expected_map());
+}
+
+#[test]
+fn load_dumps_reads_csv_without_header_in_synapse_column_order() {
+    let files = write_dumps(
+        "csv_no_header",
+        DumpFormat::Csv,
+        "3,!room:test,$alice\n1,!room:test,$create\n",
+        "3,!room:test,m.room.member,@alice:test,$alice\tjoin\n\
+         1,!room:test,m.room.topic,\"\",\"a \"\"quoted\"\",\nmultiline topic\"\n\
+         1,!room:test,m.room.create,\"\",$create\n",
+        "3,1\n",
+    );
+
+    let store = load_dumps(&files, "!room:test");
+    remove_dumps(&files);
+
+    assert_eq!(store.unwrap().state_groups(), &expected_map());
+}
+
+#[test]
+fn load_dumps_reads_pg_dump_output() {
+    // The columns are in a different order to the table to check that the
+    // header is used to find them
+    let files = write_dumps(
+        "copy",
+        DumpFormat::Copy,
+        "--\n\
+         -- PostgreSQL database dump\n\
+         --\n\
+         SET statement_timeout = 0;\n\
+         SELECT pg_catalog.set_config('search_path', '', false);\n\
+         \n\
+         COPY public.state_groups (id, room_id, event_id) FROM stdin;\n\
+         1\t!room:test\t$create\n\
+         2\t!other:test\t$other\n\
+         3\t!room:test\t$alice\n\
+         \\.\n\
+         \n\
+         -- PostgreSQL database dump complete\n",
+        "COPY public.state_groups_state (room_id, state_group, type, state_key, event_id) FROM stdin;\n\
+         !room:test\t1\tm.room.create\t\t$create\n\
+         !room:test\t1\tm.room.topic\t\ta \"quoted\",\\nmultiline topic\n\
+         !other:test\t2\tm.room.create\t\t$other\n\
+         !room:test\t3\tm.room.member\t@alice:test\t$alice\\tjoin\n\
+         \\.\n",
+        "2\t1\n3\t1\n",
+    );
+
+    let store = load_dumps(&files, "!room:test");
+    remove_dumps(&files);
+
+    assert_eq!(store.unwrap().state_groups(), &expected_map());
+}
+
+#[test]
+fn load_dumps_reports_where_the_file_is_invalid() {
+    let files = write_dumps(
+        "invalid",
+        DumpFormat::Csv,
+        "1,!room:test,$create\n",
+        "1,!room:test,m.room.create,\"\",$create\n\
+         1,!room:test,m.room.topic,,$topic\n",
+        "",
+    );
+
+    let result = load_dumps(&files, "!room:test");
+    remove_dumps(&files);
+
+    match result {
+        Err(CompressorError::InvalidDump { file, line, .. }) => {
+            assert_eq!(file, files.state_groups_state);
+            assert_eq!(line, 2);
+        }
+        other => panic!("Expected InvalidDump error, got {:?}", other.map(|_| ())),
+    }
+}
+
+#[test]
+fn parse_copy_line_handles_nulls_and_escapes() {
+    assert_eq!(
+        parse_copy_line("1\t\\N\t\t\\\\x\\101\\x42").unwrap(),
+        vec![
+            Some("1".to_string()),
+            None,
+            Some("".to_string()),
+            Some("\\xAB".to_string()),
+        ]
+    );
+}
+
+#[test]
+fn unescape_copy_field_rejects_trailing_backslash() {
+    assert!(unescape_copy_field("abc\\").is_err());
+}
@@ -0,0 +1,110 @@
+//! The errors that can be returned by the compressor.
+
+use openssl::error::ErrorStack;
+use std::{error::Error, fmt, io};
+
+use crate::diff::{StateGroupMismatch, MAX_DISPLAYED};
+
+/// Everything that can go wrong while running the compressor
+#[derive(Debug)]
+pub enum CompressorError {
+    /// Couldn't connect to the database
+    Connect(postgres::Error),
+    /// Couldn't set up TLS for the connection to the database
+    Tls(ErrorStack),
+    /// A query sent to the database failed
+    Query(postgres::Error),
+    /// A state group was referenced (e.g. as a predecessor) but couldn't be found
+    MissingStateGroup(i64),
+    /// The state of some groups after compressing doesn't match the state
+    /// before
+    VerificationMismatch { mismatches: Vec<StateGroupMismatch> },
+    /// After committing, the state of these groups in the database no longer
+    /// matches their state before compressing
+    CommittedStateMismatch { state_groups: Vec<i64> },
+    /// Reading or writing a file failed
+    Io(io::Error),
+    /// A file exported from the database couldn't be parsed
+    InvalidDump {
+        file: String,
+        line: usize,
+        reason: String,
+    },
+    /// A snapshot of a room's state groups couldn't be read
+    InvalidSnapshot(String),
+    /// The options given to the compressor were invalid
+    Config(String),
+    /// There were no state groups in the range that was asked for
+    NoStateGroups,
+}
+
+impl fmt::Display for CompressorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompressorError::Connect(e) => write!(f, "Error connecting to the database: {}", e),
+            CompressorError::Tls(e) => write!(f, "Error setting up TLS: {}", e),
+            CompressorError::Query(e) => write!(f, "Error querying the database: {}", e),
+            CompressorError::MissingStateGroup(sg) => write!(f, "Missing state group {}", sg),
+            CompressorError::VerificationMismatch { mismatches } => {
+                write!(f, "States for {} groups do not match", mismatches.len())?;
+                for mismatch in mismatches.iter().take(MAX_DISPLAYED) {
+                    write!(f, "\n{}", mismatch)?;
+                }
+                if mismatches.len() > MAX_DISPLAYED {
+                    write!(f, "\n... and {} more", mismatches.len() - MAX_DISPLAYED)?;
+                }
+                Ok(())
+            }
+            CompressorError::CommittedStateMismatch { state_groups } => {
+                write!(
+                    f,
+                    "States for {} groups do not match after committing: {:?}",
+                    state_groups.len(),
+                    &state_groups[..state_groups.len().min(MAX_DISPLAYED)]
+                )?;
+                if state_groups.len() > MAX_DISPLAYED {
+                    write!(f, "\n... and {} more", state_groups.len() - MAX_DISPLAYED)?;
+                }
+                Ok(())
+            }
+            CompressorError::Io(e) => write!(f, "IO error: {}", e),
+            CompressorError::InvalidDump { file, line, reason } => {
+                write!(f, "Invalid dump file {} (line {}): {}", file, line, reason)
+            }
+            CompressorError::InvalidSnapshot(e) => write!(f, "Invalid snapshot: {}", e),
+            CompressorError::Config(e) => write!(f, "Invalid config: {}", e),
+            CompressorError::NoStateGroups => write!(f, "No state groups found within this range"),
+        }
+    }
+}
+
+impl Error for CompressorError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            CompressorError::Connect(e) | CompressorError::Query(e) => Some(e),
+            CompressorError::Tls(e) => Some(e),
+            CompressorError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+// Any error from a query is a Query error. Connection errors are mapped
+// explicitly where the connection is made.
+impl From<postgres::Error> for CompressorError {
+    fn from(e: postgres::Error) -> Self {
+        CompressorError::Query(e)
+    }
+}
+
+impl From<io::Error> for CompressorError {
+    fn from(e: io::Error) -> Self {
+        CompressorError::Io(e)
+    }
+}
+
+impl From<ErrorStack> for CompressorError {
+    fn from(e: ErrorStack) -> Self {
+        CompressorError::Tls(e)
+    }
+}
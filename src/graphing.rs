@@ -1,5 +1,8 @@
 use std::collections::BTreeMap;
-use std::{fs::File, io::Write};
+use std::{
+    fs::File,
+    io::{self, Write},
+};
 
 use super::StateGroupEntry;
 
@@ -14,21 +17,21 @@ type Graph = BTreeMap<i64, StateGroupEntry>;
 /// * `groups`          - A map from state group ids to StateGroupEntries
 /// * `edges_output`    - The file to output the predecessor link information to
 /// * `nodes_output`    - The file to output the state group information to
-fn output_csv(groups: &Graph, edges_output: &mut File, nodes_output: &mut File) {
+fn output_csv(groups: &Graph, edges_output: &mut File, nodes_output: &mut File) -> io::Result<()> {
     // The line A;B in the edges file means:
     //      That state group A has predecessor B
-    writeln!(edges_output, "Source;Target",).unwrap();
+    writeln!(edges_output, "Source;Target",)?;
 
     // The line A;B;C;"B" in the nodes file means:
     //      The state group id is A
     //      This state group has B rows in the state_groups_state table
     //      If C is true then A has no predecessor
-    writeln!(nodes_output, "Id;Rows;Root;Label",).unwrap();
+    writeln!(nodes_output, "Id;Rows;Root;Label",)?;
 
     for (source, entry) in groups {
         // If the group has a predecessor then write an edge in the edges file
         if let Some(target) = entry.prev_state_group {
-            writeln!(edges_output, "{};{}", source, target,).unwrap();
+            writeln!(edges_output, "{};{}", source, target,)?;
         }
 
         // Write the state group's information to the nodes file
@@ -39,9 +42,10 @@ fn output_csv(groups: &Graph, edges_output: &mut File, nodes_output: &mut File)
             entry.state_map.len(),
             entry.prev_state_group.is_none(),
             entry.state_map.len(),
-        )
-        .unwrap();
+        )?;
     }
+
+    Ok(())
 }
 
 /// Outputs information from two state group graph into files
@@ -57,15 +61,15 @@ fn output_csv(groups: &Graph, edges_output: &mut File, nodes_output: &mut File)
 /// * `after`       - A map from state group ids to StateGroupEntries
 ///                   the information from this map goes into after_edges.csv
 ///                   and after_nodes.csv
-pub fn make_graphs(before: &Graph, after: &Graph) {
+pub fn make_graphs(before: &Graph, after: &Graph) -> io::Result<()> {
     // Open all the files to output to
-    let mut before_edges_file = File::create("before_edges.csv").unwrap();
-    let mut before_nodes_file = File::create("before_nodes.csv").unwrap();
-    let mut after_edges_file = File::create("after_edges.csv").unwrap();
-    let mut after_nodes_file = File::create("after_nodes.csv").unwrap();
+    let mut before_edges_file = File::create("before_edges.csv")?;
+    let mut before_nodes_file = File::create("before_nodes.csv")?;
+    let mut after_edges_file = File::create("after_edges.csv")?;
+    let mut after_nodes_file = File::create("after_nodes.csv")?;
 
     // Write before's information to before_edges and before_nodes
-    output_csv(before, &mut before_edges_file, &mut before_nodes_file);
+    output_csv(before, &mut before_edges_file, &mut before_nodes_file)?;
     // Write afters's information to after_edges and after_nodes
-    output_csv(after, &mut after_edges_file, &mut after_nodes_file);
+    output_csv(after, &mut after_edges_file, &mut after_nodes_file)
 }
@@ -20,7 +20,7 @@
 #[global_allocator]
 static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
 
-use log::LevelFilter;
+use log::{error, LevelFilter};
 use std::env;
 use std::io::Write;
 
@@ -42,5 +42,8 @@ fn main() {
         env_logger::Builder::from_env("RUST_LOG").init();
     }
 
-    comp_state::run(comp_state::Config::parse_arguments());
+    if let Err(e) = comp_state::run(comp_state::Config::parse_arguments()) {
+        error!("{}", e);
+        std::process::exit(1);
+    }
 }
@@ -0,0 +1,366 @@
+//! A compact file format for the state groups loaded for a room.
+//!
+//! Saving the map that was loaded from the database means the compressor can
+//! be run on it again (for example with different level sizes) without
+//! touching the database, and gives a reproducible fixture to attach to bug
+//! reports.
+//!
+//! The file starts with `MAGIC` and a version byte. The rest of it is made up
+//! of unsigned LEB128 varints (signed values are zigzag encoded first), with
+//! strings written as their length followed by their UTF-8 bytes:
+//!
+//! ```text
+//! room_id             string
+//! max_group_found     signed
+//! string count        unsigned
+//! strings             string * count
+//! group count         unsigned
+//! groups              group * count
+//! ```
+//!
+//! Every type, state_key and event_id is written once in the string table and
+//! referred to by its index. Each group is written as:
+//!
+//! ```text
+//! id                  signed, the difference from the id of the previous group
+//! flags               1 if in_range, plus 2 if it has a predecessor
+//! prev_state_group    signed, the difference from id (only if it has one)
+//! delta count         unsigned
+//! deltas              (type, state_key, event_id) string indices * count
+//! ```
+
+use state_map::StateMap;
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{ErrorKind, Read, Write},
+};
+use string_cache::DefaultAtom as Atom;
+
+use crate::{
+    collapse::{CollapseCache, DEFAULT_COLLAPSE_CACHE_SIZE},
+    compressor::Level,
+    CompressorError, StateGroupEntry, StateGroupStore,
+};
+
+/// The bytes every snapshot file starts with
+const MAGIC: &[u8] = b"SYNSTATE";
+
+/// The version of the format written by `write_snapshot`
+const VERSION: u8 = 1;
+
+const IN_RANGE: u8 = 1;
+const HAS_PREV: u8 = 2;
+
+/// The state groups loaded for a room, as read back from a snapshot file
+///
+/// This implements `StateGroupStore`, so it can be given to `run_with_store`
+/// in place of the database. It always returns exactly the state groups that
+/// were saved (including which ones are in range), whatever range is asked
+/// for, and changes can't be committed to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub room_id: String,
+    pub max_group_found: i64,
+    pub state_group_map: BTreeMap<i64, StateGroupEntry>,
+}
+
+/// Writes the state groups loaded for a room in the snapshot format
+///
+/// # Arguments
+///
+/// * `writer`          -   Where to write the snapshot to
+/// * `room_id`         -   The room the state groups belong to
+/// * `max_group_found` -   The last group that was loaded as being in range
+/// * `state_group_map` -   The state groups that were loaded
+pub fn write_snapshot<W: Write>(
+    mut writer: W,
+    room_id: &str,
+    max_group_found: i64,
+    state_group_map: &BTreeMap<i64, StateGroupEntry>,
+) -> Result<(), CompressorError> {
+    let mut strings = StringTable::default();
+    let mut groups = Vec::new();
+    let mut last_sg: i64 = 0;
+    for (sg, entry) in state_group_map {
+        write_signed(&mut groups, sg - last_sg)?;
+        last_sg = *sg;
+
+        let mut flags = 0;
+        if entry.in_range {
+            flags |= IN_RANGE;
+        }
+        if entry.prev_state_group.is_some() {
+            flags |= HAS_PREV;
+        }
+        groups.write_all(&[flags])?;
+        if let Some(prev_sg) = entry.prev_state_group {
+            write_signed(&mut groups, sg - prev_sg)?;
+        }
+
+        write_unsigned(&mut groups, entry.state_map.len() as u64)?;
+        for ((t, s), e) in entry.state_map.iter() {
+            write_unsigned(&mut groups, strings.intern(t))?;
+            write_unsigned(&mut groups, strings.intern(s))?;
+            write_unsigned(&mut groups, strings.intern(e))?;
+        }
+    }
+
+    writer.write_all(MAGIC)?;
+    writer.write_all(&[VERSION])?;
+    write_string(&mut writer, room_id)?;
+    write_signed(&mut writer, max_group_found)?;
+
+    write_unsigned(&mut writer, strings.strings.len() as u64)?;
+    for s in strings.strings {
+        write_string(&mut writer, s)?;
+    }
+
+    write_unsigned(&mut writer, state_group_map.len() as u64)?;
+    writer.write_all(&groups)?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// Reads a snapshot written by `write_snapshot`
+///
+/// # Arguments
+///
+/// * `reader`  -   Where to read the snapshot from
+pub fn read_snapshot<R: Read>(mut reader: R) -> Result<Snapshot, CompressorError> {
+    let mut magic = [0; MAGIC.len()];
+    read_exact(&mut reader, &mut magic)?;
+    if magic != MAGIC {
+        return Err(invalid("not a state group snapshot"));
+    }
+
+    let mut version = [0];
+    read_exact(&mut reader, &mut version)?;
+    if version[0] != VERSION {
+        return Err(invalid(&format!(
+            "unsupported version {} (expected {})",
+            version[0], VERSION
+        )));
+    }
+
+    let room_id = read_string(&mut reader)?;
+    let max_group_found = read_signed(&mut reader)?;
+
+    let string_count = read_unsigned(&mut reader)?;
+    let strings = (0..string_count)
+        .map(|_| read_string(&mut reader).map(Atom::from))
+        .collect::<Result<Vec<Atom>, _>>()?;
+    let string = |index: u64| {
+        strings
+            .get(index as usize)
+            .ok_or_else(|| invalid(&format!("no string with index {}", index)))
+    };
+
+    let mut state_group_map = BTreeMap::new();
+    let mut last_sg: i64 = 0;
+    for _ in 0..read_unsigned(&mut reader)? {
+        let sg = last_sg
+            .checked_add(read_signed(&mut reader)?)
+            .ok_or_else(|| invalid("state group id out of range"))?;
+        last_sg = sg;
+
+        let mut flags = [0];
+        read_exact(&mut reader, &mut flags)?;
+
+        let prev_state_group = if flags[0] & HAS_PREV != 0 {
+            let prev = sg
+                .checked_sub(read_signed(&mut reader)?)
+                .ok_or_else(|| invalid("state group id out of range"))?;
+            Some(prev)
+        } else {
+            None
+        };
+
+        let mut state_map = StateMap::new();
+        for _ in 0..read_unsigned(&mut reader)? {
+            let t = string(read_unsigned(&mut reader)?)?;
+            let s = string(read_unsigned(&mut reader)?)?;
+            let e = string(read_unsigned(&mut reader)?)?;
+            state_map.insert(t, s, e.clone());
+        }
+
+        state_group_map.insert(
+            sg,
+            StateGroupEntry {
+                in_range: flags[0] & IN_RANGE != 0,
+                prev_state_group,
+                state_map,
+            },
+        );
+    }
+
+    Ok(Snapshot {
+        room_id,
+        max_group_found,
+        state_group_map,
+    })
+}
+
+impl StateGroupStore for Snapshot {
+    fn find_max_group(
+        &mut self,
+        _room_id: &str,
+        _min_state_group: Option<i64>,
+        _groups_to_compress: Option<i64>,
+        _max_state_group: Option<i64>,
+    ) -> Result<Option<i64>, CompressorError> {
+        if self.state_group_map.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(self.max_group_found))
+        }
+    }
+
+    fn get_initial_data(
+        &mut self,
+        _room_id: &str,
+        _min_state_group: Option<i64>,
+        _max_group_found: i64,
+    ) -> Result<BTreeMap<i64, StateGroupEntry>, CompressorError> {
+        Ok(self.state_group_map.clone())
+    }
+
+    fn get_missing(
+        &mut self,
+        missing_sgs: &[i64],
+        _min_state_group: Option<i64>,
+        _max_group_found: i64,
+    ) -> Result<BTreeMap<i64, StateGroupEntry>, CompressorError> {
+        // Every predecessor was loaded before the snapshot was taken, so this
+        // only happens if the database was missing groups, which are treated
+        // as empty in the same way
+        Ok(missing_sgs
+            .iter()
+            .map(|sg| (*sg, StateGroupEntry::default()))
+            .collect())
+    }
+
+    fn load_level_heads(
+        &mut self,
+        level_info: &[Level],
+    ) -> Result<BTreeMap<i64, StateGroupEntry>, CompressorError> {
+        Ok(level_info
+            .iter()
+            .filter_map(|l| l.get_head())
+            .filter_map(|head| {
+                self.state_group_map.get(&head).map(|entry| {
+                    let mut entry = entry.clone();
+                    entry.in_range = false;
+                    (head, entry)
+                })
+            })
+            .collect())
+    }
+
+    fn send_changes(
+        &mut self,
+        _room_id: &str,
+        _old_map: &BTreeMap<i64, StateGroupEntry>,
+        _new_map: &BTreeMap<i64, StateGroupEntry>,
+    ) -> Result<(), CompressorError> {
+        Err(CompressorError::Config(
+            "Changes can't be committed to a snapshot".to_string(),
+        ))
+    }
+
+    fn get_collapsed_state(
+        &mut self,
+        state_groups: &[i64],
+    ) -> Result<BTreeMap<i64, StateMap<Atom>>, CompressorError> {
+        let mut cache = CollapseCache::new(&self.state_group_map, DEFAULT_COLLAPSE_CACHE_SIZE);
+
+        state_groups
+            .iter()
+            .map(|sg| Ok((*sg, (*cache.collapse(*sg)?).clone())))
+            .collect()
+    }
+}
+
+/// The strings used by the deltas of a snapshot, in the order they were
+/// first seen
+#[derive(Default)]
+struct StringTable<'a> {
+    strings: Vec<&'a str>,
+    indices: HashMap<&'a str, u64>,
+}
+
+impl<'a> StringTable<'a> {
+    /// Returns the index of `s`, adding it to the table if it's new
+    fn intern(&mut self, s: &'a str) -> u64 {
+        let strings = &mut self.strings;
+        *self.indices.entry(s).or_insert_with(|| {
+            strings.push(s);
+            strings.len() as u64 - 1
+        })
+    }
+}
+
+fn invalid(reason: &str) -> CompressorError {
+    CompressorError::InvalidSnapshot(reason.to_string())
+}
+
+fn write_unsigned<W: Write>(writer: &mut W, mut value: u64) -> Result<(), CompressorError> {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            writer.write_all(&[byte])?;
+            return Ok(());
+        }
+        writer.write_all(&[byte | 0x80])?;
+    }
+}
+
+fn write_signed<W: Write>(writer: &mut W, value: i64) -> Result<(), CompressorError> {
+    write_unsigned(writer, ((value << 1) ^ (value >> 63)) as u64)
+}
+
+fn write_string<W: Write>(writer: &mut W, value: &str) -> Result<(), CompressorError> {
+    write_unsigned(writer, value.len() as u64)?;
+    writer.write_all(value.as_bytes())?;
+    Ok(())
+}
+
+/// Fills `buf`, treating running out of file as the snapshot being invalid
+fn read_exact<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<(), CompressorError> {
+    reader.read_exact(buf).map_err(|e| match e.kind() {
+        ErrorKind::UnexpectedEof => invalid("unexpected end of file"),
+        _ => e.into(),
+    })
+}
+
+fn read_unsigned<R: Read>(reader: &mut R) -> Result<u64, CompressorError> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0];
+        read_exact(reader, &mut byte)?;
+        value |= u64::from(byte[0] & 0x7f) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(invalid("varint is too long"))
+}
+
+fn read_signed<R: Read>(reader: &mut R) -> Result<i64, CompressorError> {
+    let value = read_unsigned(reader)?;
+    Ok((value >> 1) as i64 ^ -((value & 1) as i64))
+}
+
+fn read_string<R: Read>(reader: &mut R) -> Result<String, CompressorError> {
+    let len = read_unsigned(reader)?;
+    let mut bytes = Vec::new();
+    reader.take(len).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != len {
+        return Err(invalid("unexpected end of file"));
+    }
+    String::from_utf8(bytes).map_err(|_| invalid("string is not valid UTF-8"))
+}
+
+#[cfg(test)]
+mod snapshot_tests;
@@ -0,0 +1,150 @@
+use crate::{
+    snapshot::{
+        read_snapshot, write_signed, write_snapshot, write_unsigned, Snapshot, HAS_PREV, MAGIC,
+        VERSION,
+    },
+    store::get_data_from_store,
+    CompressorError, StateGroupEntry, StateGroupStore,
+};
+use state_map::StateMap;
+use std::collections::BTreeMap;
+use string_cache::DefaultAtom as Atom;
+
+/// A map like one loaded from the database, with groups 0-3 out of range
+/// (one of which isn't in order) and awkward strings
+fn example_map() -> BTreeMap<i64, StateGroupEntry> {
+    let mut map = BTreeMap::new();
+
+    for sg in 0..40i64 {
+        let mut state_map: StateMap<Atom> = StateMap::new();
+        state_map.insert("m.room.member", &format!("@{}:test", sg % 5), "$ev".into());
+        if sg % 7 == 0 {
+            state_map.insert("m.room.topic", "", "$tópic ☃\n\t\"'".into());
+        }
+
+        map.insert(
+            sg * 1000,
+            StateGroupEntry {
+                in_range: sg >= 4,
+                prev_state_group: match sg {
+                    0 => None,
+                    3 => Some(39_000),
+                    _ => Some((sg - 1) * 1000),
+                },
+                state_map,
+            },
+        );
+    }
+
+    map
+}
+
+#[test]
+fn snapshot_round_trips() {
+    let map = example_map();
+
+    let mut bytes = Vec::new();
+    write_snapshot(&mut bytes, "!room:test", 39_000, &map).unwrap();
+
+    let snapshot = read_snapshot(&bytes[..]).unwrap();
+
+    assert_eq!(
+        snapshot,
+        Snapshot {
+            room_id: "!room:test".to_string(),
+            max_group_found: 39_000,
+            state_group_map: map,
+        }
+    );
+}
+
+#[test]
+fn snapshot_writes_each_string_once() {
+    let map = example_map();
+
+    let mut bytes = Vec::new();
+    write_snapshot(&mut bytes, "!room:test", 39_000, &map).unwrap();
+
+    let text = String::from_utf8_lossy(&bytes);
+    assert_eq!(text.matches("m.room.member").count(), 1);
+    assert_eq!(text.matches("$ev").count(), 1);
+}
+
+#[test]
+fn read_snapshot_rejects_other_files() {
+    match read_snapshot(&b"id,room_id,event_id\n"[..]) {
+        Err(CompressorError::InvalidSnapshot(_)) => {}
+        other => panic!("Expected InvalidSnapshot error, got {:?}", other),
+    }
+}
+
+#[test]
+fn read_snapshot_rejects_truncated_files() {
+    let mut bytes = Vec::new();
+    write_snapshot(&mut bytes, "!room:test", 39_000, &example_map()).unwrap();
+
+    for len in &[MAGIC.len() + 3, bytes.len() / 2, bytes.len() - 1] {
+        match read_snapshot(&bytes[..*len]) {
+            Err(CompressorError::InvalidSnapshot(_)) => {}
+            other => panic!("Expected InvalidSnapshot error, got {:?}", other),
+        }
+    }
+}
+
+#[test]
+fn read_snapshot_rejects_out_of_range_state_groups() {
+    // A snapshot with no strings whose first group is `first_sg`, followed by
+    // a second group that the caller writes
+    let header = |first_sg: i64| {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(VERSION);
+        write_unsigned(&mut bytes, 0).unwrap();
+        write_signed(&mut bytes, 0).unwrap();
+        write_unsigned(&mut bytes, 0).unwrap();
+        write_unsigned(&mut bytes, 2).unwrap();
+
+        write_signed(&mut bytes, first_sg).unwrap();
+        bytes.push(0);
+        write_unsigned(&mut bytes, 0).unwrap();
+        bytes
+    };
+
+    // The second group is after i64::MAX
+    let mut after_max = header(i64::MAX);
+    write_signed(&mut after_max, 1).unwrap();
+
+    // The second group's predecessor is before i64::MIN
+    let mut before_min = header(i64::MIN);
+    write_signed(&mut before_min, 0).unwrap();
+    before_min.push(HAS_PREV);
+    write_signed(&mut before_min, 1).unwrap();
+
+    for bytes in &[after_max, before_min] {
+        match read_snapshot(&bytes[..]) {
+            Err(CompressorError::InvalidSnapshot(e)) => {
+                assert_eq!(e, "state group id out of range")
+            }
+            other => panic!("Expected InvalidSnapshot error, got {:?}", other),
+        }
+    }
+}
+
+#[test]
+fn snapshot_store_returns_saved_map_for_any_range() {
+    let map = example_map();
+    let mut snapshot = Snapshot {
+        room_id: "!room:test".to_string(),
+        max_group_found: 39_000,
+        state_group_map: map.clone(),
+    };
+
+    let (loaded, max_group_found) =
+        get_data_from_store(&mut snapshot, "!room:test", Some(5), Some(2), Some(10))
+            .unwrap()
+            .unwrap();
+
+    assert_eq!(loaded, map);
+    assert_eq!(max_group_found, 39_000);
+
+    assert!(snapshot.send_changes("!room:test", &map, &map).is_err());
+}
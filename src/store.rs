@@ -0,0 +1,561 @@
+//! The storage abstraction used by the compressor.
+//!
+//! The compressor only needs to be able to do a handful of things with the
+//! place the state groups live: find out how far a chunk of a room extends,
+//! load the state groups in that chunk (and any predecessors they reference),
+//! load the heads of the levels saved by a previous run and finally write a
+//! changeset back. These are captured by the `StateGroupStore` trait.
+//!
+//! The Postgres implementation lives in `database.rs` (and is implemented
+//! directly on `postgres::Client`). `MemoryStore` is a simple implementation
+//! that holds everything in memory, which is useful for fixtures and for
+//! running the compressor on data that didn't come from a live database.
+
+use log::{debug, info, trace, warn};
+use state_map::StateMap;
+use std::collections::BTreeMap;
+use string_cache::DefaultAtom as Atom;
+
+use crate::{
+    collapse::{CollapseCache, DEFAULT_COLLAPSE_CACHE_SIZE},
+    compressor::Level,
+    diff::StateGroupMismatch,
+    CompressorError, StateGroupEntry,
+};
+
+/// Something that the compressor can load state groups from and write its
+/// changes back to.
+pub trait StateGroupStore {
+    /// Returns the group ID of the last group to be compressed
+    ///
+    /// This can be saved so that future runs of the compressor only
+    /// continue from after this point. If no groups can be found in
+    /// the range specified it returns None.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id`             -   The ID of the room in the database
+    /// * `min_state_group`     -   The lower limit (non inclusive) of group id's to compress
+    /// * 'groups_to_compress'  -   How many groups to compress
+    /// * `max_state_group`     -   The upper bound on what this method can return
+    fn find_max_group(
+        &mut self,
+        room_id: &str,
+        min_state_group: Option<i64>,
+        groups_to_compress: Option<i64>,
+        max_state_group: Option<i64>,
+    ) -> Result<Option<i64>, CompressorError>;
+
+    /// Fetch the state groups (with their predecessors and deltas) for a room
+    /// that are within the range given. Every entry returned is marked as
+    /// `in_range`.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id`         -   The ID of the room in the database
+    /// * `min_state_group` -   If specified, then only fetch the entries for state
+    ///                         groups greater than (but not equal) to this number
+    /// * 'max_group_found' -   The upper limit on state_groups ids to fetch
+    fn get_initial_data(
+        &mut self,
+        room_id: &str,
+        min_state_group: Option<i64>,
+        max_group_found: i64,
+    ) -> Result<BTreeMap<i64, StateGroupEntry>, CompressorError>;
+
+    /// Fetch specific state groups that were referenced as predecessors but
+    /// weren't returned by `get_initial_data`
+    ///
+    /// # Arguments
+    ///
+    /// * `missing_sgs`     -   An array of missing state_group ids
+    /// * 'min_state_group' -   Minimum state_group id to mark as in range
+    /// * 'max_group_found' -   Maximum state_group id to mark as in range
+    fn get_missing(
+        &mut self,
+        missing_sgs: &[i64],
+        min_state_group: Option<i64>,
+        max_group_found: i64,
+    ) -> Result<BTreeMap<i64, StateGroupEntry>, CompressorError>;
+
+    /// Fetch the state groups that are at the head of each compressor level.
+    /// These are never marked as `in_range`.
+    ///
+    /// NOTE this does not also retrieve their predecessors
+    ///
+    /// # Arguments
+    ///
+    /// * `level_info`  -   The levels who's heads are being requested
+    fn load_level_heads(
+        &mut self,
+        level_info: &[Level],
+    ) -> Result<BTreeMap<i64, StateGroupEntry>, CompressorError>;
+
+    /// Replace the stored state groups in `old_map` with the versions in
+    /// `new_map`, leaving any entries that haven't changed alone
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` -   The ID of the room in the database
+    /// * `old_map` -   The state group data originally in the store
+    /// * `new_map` -   The state group data generated by the compressor to
+    ///                 replace replace the old contents
+    fn send_changes(
+        &mut self,
+        room_id: &str,
+        old_map: &BTreeMap<i64, StateGroupEntry>,
+        new_map: &BTreeMap<i64, StateGroupEntry>,
+    ) -> Result<(), CompressorError>;
+
+    /// Gets the full state of each of the given state groups as it is
+    /// currently stored, by following the chain of predecessors and
+    /// combining the deltas
+    ///
+    /// # Arguments
+    ///
+    /// * `state_groups`    -   The state groups to collapse
+    fn get_collapsed_state(
+        &mut self,
+        state_groups: &[i64],
+    ) -> Result<BTreeMap<i64, StateMap<Atom>>, CompressorError>;
+}
+
+/// Reads the state of every group that was changed between `old_map` and
+/// `new_map` back from the store, and compares it to the state of that group
+/// in `old_map`
+///
+/// Returns every group whose state differs. The found chain of each is the
+/// one from `new_map`, which is what the store should now contain.
+///
+/// # Arguments
+///
+/// * `store`   -   The store that the changes were sent to
+/// * `old_map` -   The state group data originally in the store
+/// * `new_map` -   The state group data that was sent to the store
+pub(crate) fn find_mismatched_groups<S: StateGroupStore + ?Sized>(
+    store: &mut S,
+    old_map: &BTreeMap<i64, StateGroupEntry>,
+    new_map: &BTreeMap<i64, StateGroupEntry>,
+) -> Result<Vec<StateGroupMismatch>, CompressorError> {
+    // Only the groups that were rewritten need checking (these are the
+    // same groups that generate_sql writes changes for)
+    let changed_groups: Vec<i64> = old_map
+        .iter()
+        .filter(|(sg, old_entry)| new_map.get(sg) != Some(old_entry))
+        .map(|(sg, _)| *sg)
+        .collect();
+
+    debug!(
+        "Reading {} changed state groups back from the store...",
+        changed_groups.len()
+    );
+
+    let mut mismatches = Vec::new();
+    let mut old_cache = CollapseCache::new(old_map, DEFAULT_COLLAPSE_CACHE_SIZE);
+
+    for (sg, found) in store.get_collapsed_state(&changed_groups)? {
+        let expected = old_cache.collapse(sg)?;
+        mismatches.extend(StateGroupMismatch::compare(
+            sg, &expected, &found, old_map, new_map,
+        ));
+    }
+
+    Ok(mismatches)
+}
+
+/// Checks that the changes sent to the store by the compressor didn't alter
+/// the state of any of the groups, by reading the changed groups back once
+/// they have been committed
+///
+/// This catches anything that interfered with the changes as they were being
+/// written, such as Synapse writing to the same groups or a database trigger.
+/// Returns a `CommittedStateMismatch` error listing every group that differs.
+///
+/// # Arguments
+///
+/// * `store`   -   The store that the changes were sent to
+/// * `old_map` -   The state group data originally in the store
+/// * `new_map` -   The state group data that was sent to the store
+pub fn verify_committed<S: StateGroupStore + ?Sized>(
+    store: &mut S,
+    old_map: &BTreeMap<i64, StateGroupEntry>,
+    new_map: &BTreeMap<i64, StateGroupEntry>,
+) -> Result<(), CompressorError> {
+    info!("Checking that the committed state groups match...");
+
+    let mismatches = find_mismatched_groups(store, old_map, new_map)?;
+
+    if mismatches.is_empty() {
+        info!("Committed state groups match the original ones");
+        return Ok(());
+    }
+
+    for mismatch in &mismatches {
+        warn!("Mismatch after committing: {}", mismatch);
+    }
+
+    Err(CompressorError::CommittedStateMismatch {
+        state_groups: mismatches.iter().map(|m| m.state_group).collect(),
+    })
+}
+
+/// The state groups loaded for a chunk, along with the ID of the last group
+/// in that chunk
+pub type LoadedData = (BTreeMap<i64, StateGroupEntry>, i64);
+
+/// Fetch the entries in state_groups_state (and their prev groups) for a
+/// specific room.
+///
+/// Returns with the state_group map and the id of the last group that was used
+/// Or None if there are no state groups within the range given
+///
+/// # Arguments
+///
+/// * `store`               -   The store to load the state groups from
+/// * `room_id`             -   The ID of the room in the database
+/// * `min_state_group`     -   If specified, then only fetch the entries for state
+///                             groups greater than (but not equal) to this number. It
+///                             also requires groups_to_compress to be specified
+/// * 'groups_to_compress'  -   The number of groups to get from the database before stopping
+/// * `max_state_group`     -   If specified, then only fetch the entries for state
+///                             groups lower than or equal to this number.
+pub fn get_data_from_store<S: StateGroupStore + ?Sized>(
+    store: &mut S,
+    room_id: &str,
+    min_state_group: Option<i64>,
+    groups_to_compress: Option<i64>,
+    max_state_group: Option<i64>,
+) -> Result<Option<LoadedData>, CompressorError> {
+    // Search for the group id of the groups_to_compress'th group after min_state_group
+    // If this is saved, then the compressor can continue by having min_state_group being
+    // set to this maximum. If no such group can be found then return None.
+    let max_group_found = match store.find_max_group(
+        room_id,
+        min_state_group,
+        groups_to_compress,
+        max_state_group,
+    )? {
+        Some(max) => max,
+        None => return Ok(None),
+    };
+
+    let state_group_map: BTreeMap<i64, StateGroupEntry> = BTreeMap::new();
+
+    load_map_from_store(
+        store,
+        room_id,
+        min_state_group,
+        max_group_found,
+        state_group_map,
+    )
+    .map(Some)
+}
+
+/// Fetch the entries in state_groups_state (and their prev groups) for a
+/// specific room. This method should only be called if resuming the compressor from
+/// where it last finished - and as such also loads in the state groups from the heads
+/// of each of the levels (as they were at the end of the last run of the compressor)
+///
+/// Returns with the state_group map and the id of the last group that was used
+/// Or None if there are no state groups within the range given
+///
+/// # Arguments
+///
+/// * `store`               -   The store to load the state groups from
+/// * `room_id`             -   The ID of the room in the database
+/// * `min_state_group`     -   If specified, then only fetch the entries for state
+///                             groups greater than (but not equal) to this number. It
+///                             also requires groups_to_compress to be specified
+/// * 'groups_to_compress'  -   The number of groups to get from the database before stopping
+/// * 'level_info'          -   The maximum size, current length and current head for each
+///                             level (as it was when the compressor last finished for this
+///                             room)
+pub fn reload_data_from_store<S: StateGroupStore + ?Sized>(
+    store: &mut S,
+    room_id: &str,
+    min_state_group: Option<i64>,
+    groups_to_compress: Option<i64>,
+    level_info: &[Level],
+) -> Result<Option<LoadedData>, CompressorError> {
+    // Search for the group id of the groups_to_compress'th group after min_state_group
+    // If this is saved, then the compressor can continue by having min_state_group being
+    // set to this maximum.If no such group can be found then return None.
+    let max_group_found = match store.find_max_group(
+        room_id,
+        min_state_group,
+        groups_to_compress,
+        // max state group not used when saving and loading
+        None,
+    )? {
+        Some(max) => max,
+        None => return Ok(None),
+    };
+
+    // load just the state_groups at the head of each level
+    // this doesn't load their predecessors as that will be done at the end of
+    // load_map_from_store()
+    let state_group_map: BTreeMap<i64, StateGroupEntry> = store.load_level_heads(level_info)?;
+
+    load_map_from_store(
+        store,
+        room_id,
+        min_state_group,
+        max_group_found,
+        state_group_map,
+    )
+    .map(Some)
+}
+
+/// Fetch the entries in state_groups_state (and their prev groups) for a
+/// specific room within a certain range. These are appended onto the provided
+/// map.
+///
+/// - Fetches the first [group] rows with group id after [min]
+/// - Recursively searches for missing predecessors and adds those
+///
+/// Returns with the state_group map and the id of the last group that was used
+///
+/// # Arguments
+///
+/// * `store`               -   The store to load the state groups from
+/// * `room_id`             -   The ID of the room in the database
+/// * `min_state_group`     -   If specified, then only fetch the entries for state
+///                             groups greater than (but not equal) to this number. It
+///                             also requires groups_to_compress to be specified
+/// * 'max_group_found'     -   The last group to get from the database before stopping
+/// * 'state_group_map'     -   The map to populate with the entries from the database
+fn load_map_from_store<S: StateGroupStore + ?Sized>(
+    store: &mut S,
+    room_id: &str,
+    min_state_group: Option<i64>,
+    max_group_found: i64,
+    mut state_group_map: BTreeMap<i64, StateGroupEntry>,
+) -> Result<(BTreeMap<i64, StateGroupEntry>, i64), CompressorError> {
+    state_group_map.append(&mut store.get_initial_data(
+        room_id,
+        min_state_group,
+        max_group_found,
+    )?);
+
+    debug!("Got initial state from database. Checking for any missing state groups...");
+
+    // Due to reasons some of the state groups appear in the edges table, but
+    // not in the state_groups_state table.
+    //
+    // Also it is likely that the predecessor of a node will not be within the
+    // chunk that was specified by min_state_group and groups_to_compress.
+    // This means they don't get included in our DB queries, so we have to fetch
+    // any missing groups explicitly.
+    //
+    // Since the returned groups may themselves reference groups we don't have,
+    // we need to do this recursively until we don't find any more missing.
+    loop {
+        let mut missing_sgs: Vec<_> = state_group_map
+            .iter()
+            .filter_map(|(_sg, entry)| {
+                if let Some(prev_sg) = entry.prev_state_group {
+                    if state_group_map.contains_key(&prev_sg) {
+                        None
+                    } else {
+                        Some(prev_sg)
+                    }
+                } else {
+                    None
+                }
+            })
+            .collect();
+
+        if missing_sgs.is_empty() {
+            trace!("No missing state groups");
+            break;
+        }
+
+        missing_sgs.sort_unstable();
+        missing_sgs.dedup();
+
+        trace!("Missing {} state groups", missing_sgs.len());
+
+        // find state groups not picked up already and add them to the map
+        let map = store.get_missing(&missing_sgs, min_state_group, max_group_found)?;
+        for (k, v) in map {
+            state_group_map.entry(k).or_insert(v);
+        }
+    }
+
+    Ok((state_group_map, max_group_found))
+}
+
+/// A `StateGroupStore` that keeps all of its state groups in memory
+///
+/// Entries are stored as they would be in the database: a predecessor and
+/// a delta for each state group, along with the room it belongs to. The
+/// `in_range` field of the stored entries is ignored.
+#[derive(Default, Debug, Clone)]
+pub struct MemoryStore {
+    rooms: BTreeMap<i64, String>,
+    state_groups: BTreeMap<i64, StateGroupEntry>,
+}
+
+impl MemoryStore {
+    /// Creates an empty store
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+
+    /// Adds (or replaces) the state groups in `state_group_map` as belonging
+    /// to the room `room_id`
+    pub fn add_room(&mut self, room_id: &str, state_group_map: &BTreeMap<i64, StateGroupEntry>) {
+        for (sg, entry) in state_group_map {
+            self.rooms.insert(*sg, room_id.to_string());
+            self.state_groups.insert(
+                *sg,
+                StateGroupEntry {
+                    in_range: false,
+                    prev_state_group: entry.prev_state_group,
+                    state_map: entry.state_map.clone(),
+                },
+            );
+        }
+    }
+
+    /// The state groups currently held by the store
+    pub fn state_groups(&self) -> &BTreeMap<i64, StateGroupEntry> {
+        &self.state_groups
+    }
+
+    /// Iterates through the ids of the state groups belonging to a room
+    fn room_groups<'a>(&'a self, room_id: &'a str) -> impl Iterator<Item = i64> + 'a {
+        self.rooms
+            .iter()
+            .filter(move |(_, r)| r.as_str() == room_id)
+            .map(|(sg, _)| *sg)
+    }
+}
+
+impl StateGroupStore for MemoryStore {
+    fn find_max_group(
+        &mut self,
+        room_id: &str,
+        min_state_group: Option<i64>,
+        groups_to_compress: Option<i64>,
+        max_state_group: Option<i64>,
+    ) -> Result<Option<i64>, CompressorError> {
+        let mut ids: Vec<i64> = self
+            .room_groups(room_id)
+            .filter(|sg| match max_state_group {
+                Some(max) => *sg <= max,
+                None => true,
+            })
+            .collect();
+
+        // As with the Postgres store, a min state group is only used if
+        // groups_to_compress also is
+        if let Some(count) = groups_to_compress {
+            if let Some(min) = min_state_group {
+                ids.retain(|sg| *sg > min);
+            }
+            ids.truncate(count.max(0) as usize);
+        }
+
+        Ok(ids.last().copied())
+    }
+
+    fn get_initial_data(
+        &mut self,
+        room_id: &str,
+        min_state_group: Option<i64>,
+        max_group_found: i64,
+    ) -> Result<BTreeMap<i64, StateGroupEntry>, CompressorError> {
+        Ok(self
+            .room_groups(room_id)
+            .filter(|sg| match min_state_group {
+                Some(min) => min < *sg && *sg <= max_group_found,
+                None => *sg <= max_group_found,
+            })
+            .map(|sg| {
+                let entry = &self.state_groups[&sg];
+                (
+                    sg,
+                    StateGroupEntry {
+                        in_range: true,
+                        prev_state_group: entry.prev_state_group,
+                        state_map: entry.state_map.clone(),
+                    },
+                )
+            })
+            .collect())
+    }
+
+    fn get_missing(
+        &mut self,
+        missing_sgs: &[i64],
+        min_state_group: Option<i64>,
+        max_group_found: i64,
+    ) -> Result<BTreeMap<i64, StateGroupEntry>, CompressorError> {
+        Ok(missing_sgs
+            .iter()
+            .map(|sg| {
+                // Groups that don't exist are treated as empty snapshots, in the
+                // same way the Postgres store treats groups that only appear in
+                // the edges table
+                let mut entry = self.state_groups.get(sg).cloned().unwrap_or_default();
+                entry.in_range = match min_state_group {
+                    Some(min) => min < *sg && *sg <= max_group_found,
+                    None => false,
+                };
+                (*sg, entry)
+            })
+            .collect())
+    }
+
+    fn load_level_heads(
+        &mut self,
+        level_info: &[Level],
+    ) -> Result<BTreeMap<i64, StateGroupEntry>, CompressorError> {
+        Ok(level_info
+            .iter()
+            .filter_map(|l| l.get_head())
+            .filter_map(|head| {
+                self.state_groups
+                    .get(&head)
+                    .map(|entry| (head, entry.clone()))
+            })
+            .collect())
+    }
+
+    fn send_changes(
+        &mut self,
+        _room_id: &str,
+        old_map: &BTreeMap<i64, StateGroupEntry>,
+        new_map: &BTreeMap<i64, StateGroupEntry>,
+    ) -> Result<(), CompressorError> {
+        for (sg, old_entry) in old_map {
+            let new_entry = new_map
+                .get(sg)
+                .ok_or(CompressorError::MissingStateGroup(*sg))?;
+
+            if old_entry != new_entry {
+                let stored = self.state_groups.entry(*sg).or_default();
+                stored.prev_state_group = new_entry.prev_state_group;
+                stored.state_map = new_entry.state_map.clone();
+            }
+        }
+
+        Ok(())
+    }
+
+    fn get_collapsed_state(
+        &mut self,
+        state_groups: &[i64],
+    ) -> Result<BTreeMap<i64, StateMap<Atom>>, CompressorError> {
+        let mut cache = CollapseCache::new(&self.state_groups, DEFAULT_COLLAPSE_CACHE_SIZE);
+
+        state_groups
+            .iter()
+            .map(|sg| Ok((*sg, (*cache.collapse(*sg)?).clone())))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod store_tests;
@@ -0,0 +1,252 @@
+use crate::{
+    collapse_state_maps, continue_run_with_store,
+    store::{get_data_from_store, verify_committed, MemoryStore, StateGroupStore},
+    CompressorError, Level, StateGroupEntry,
+};
+use state_map::StateMap;
+use std::collections::BTreeMap;
+
+/// Builds the following structure
+///
+/// 0-1-2-3-4-5-6-7-8-9-10-11-12-13
+///
+/// Where each group i has state:
+///     ('node','is',      i)
+///     ('group',  j, 'seen') - for all j less than i
+fn line_with_state() -> BTreeMap<i64, StateGroupEntry> {
+    let mut initial: BTreeMap<i64, StateGroupEntry> = BTreeMap::new();
+    let mut prev = None;
+
+    for i in 0i64..=13i64 {
+        let mut entry = StateGroupEntry {
+            in_range: true,
+            prev_state_group: prev,
+            state_map: StateMap::new(),
+        };
+        entry
+            .state_map
+            .insert("group", &i.to_string(), "seen".into());
+        entry.state_map.insert("node", "is", i.to_string().into());
+
+        initial.insert(i, entry);
+
+        prev = Some(i)
+    }
+
+    initial
+}
+
+/// Builds the following structure
+///
+/// 0-1-2 3-4-5 6-7-8 9-10-11 12-13
+///
+/// Where each group i has state:
+///     ('node','is',      i)
+///     ('group',  j, 'seen') - for all j less than i
+fn line_segments_with_state() -> BTreeMap<i64, StateGroupEntry> {
+    let mut initial: BTreeMap<i64, StateGroupEntry> = BTreeMap::new();
+    let mut prev = None;
+
+    for i in 0i64..=13i64 {
+        // if the state is a snapshot then set its predecessor to NONE
+        if i % 3 == 0 {
+            prev = None;
+        }
+
+        let mut entry = StateGroupEntry {
+            in_range: true,
+            prev_state_group: prev,
+            state_map: StateMap::new(),
+        };
+
+        // if it's a snapshot then add in all previous state
+        if prev.is_none() {
+            for j in 0..i {
+                entry
+                    .state_map
+                    .insert("group", &j.to_string(), "seen".into());
+            }
+        }
+
+        entry
+            .state_map
+            .insert("group", &i.to_string(), "seen".into());
+        entry.state_map.insert("node", "is", i.to_string().into());
+
+        initial.insert(i, entry);
+
+        prev = Some(i)
+    }
+
+    initial
+}
+
+#[test]
+fn find_max_group_respects_chunks() {
+    let mut store = MemoryStore::new();
+    store.add_room("room1", &line_with_state());
+
+    let mut max_group = |min, count, max| store.find_max_group("room1", min, count, max).unwrap();
+
+    assert_eq!(max_group(None, None, None), Some(13));
+    assert_eq!(max_group(None, Some(7), None), Some(6));
+    assert_eq!(max_group(Some(6), Some(7), None), Some(13));
+    assert_eq!(max_group(None, None, Some(4)), Some(4));
+    assert_eq!(max_group(Some(13), Some(7), None), None);
+
+    assert_eq!(
+        store.find_max_group("room2", None, None, None).unwrap(),
+        None
+    );
+}
+
+#[test]
+fn get_data_from_store_loads_missing_predecessors() {
+    let mut store = MemoryStore::new();
+    store.add_room("room1", &line_with_state());
+
+    let (map, max_group_found) = get_data_from_store(&mut store, "room1", Some(9), Some(3), None)
+        .unwrap()
+        .expect("expected to find groups");
+
+    assert_eq!(max_group_found, 12);
+
+    // All of the predecessors are loaded, but only 10, 11 and 12 are in range
+    assert_eq!(map.len(), 13);
+    for (sg, entry) in &map {
+        assert_eq!(entry.in_range, 10 <= *sg && *sg <= 12, "group {}", sg);
+    }
+}
+
+#[test]
+fn continue_run_with_store_compresses_in_memory() {
+    let initial = line_segments_with_state();
+
+    let mut store = MemoryStore::new();
+    store.add_room("room1", &initial);
+
+    let level_info = vec![Level::new(3), Level::new(3)];
+    let chunk_stats_1 = continue_run_with_store(None, 7, &mut store, "room1", &level_info, true)
+        .unwrap()
+        .expect("expected first chunk to be compressed");
+
+    assert!(chunk_stats_1.commited);
+    assert_eq!(chunk_stats_1.last_compressed_group, 6);
+
+    let chunk_stats_2 = continue_run_with_store(
+        Some(6),
+        7,
+        &mut store,
+        "room1",
+        &chunk_stats_1.new_level_info,
+        true,
+    )
+    .unwrap()
+    .expect("expected second chunk to be compressed");
+
+    assert!(chunk_stats_2.commited);
+    assert_eq!(chunk_stats_2.last_compressed_group, 13);
+
+    // This should have created the following structure in the store
+    //
+    // 0  3\      12
+    // 1  4 6\    13
+    // 2  5 7 9
+    //      8 10
+    //        11
+    let expected_edges: BTreeMap<i64, i64> = vec![
+        (1, 0),
+        (2, 1),
+        (4, 3),
+        (5, 4),
+        (6, 3),
+        (7, 6),
+        (8, 7),
+        (9, 6),
+        (10, 9),
+        (11, 10),
+        (13, 12),
+    ]
+    .into_iter()
+    .collect();
+
+    let compressed = store.state_groups();
+    for sg in 0i64..=13i64 {
+        assert_eq!(
+            expected_edges.get(&sg).cloned(),
+            compressed[&sg].prev_state_group,
+            "state group {} did not match expected",
+            sg,
+        );
+
+        // and the state itself hasn't changed
+        assert_eq!(
+            collapse_state_maps(&initial, sg).unwrap(),
+            collapse_state_maps(compressed, sg).unwrap()
+        );
+    }
+
+    // There is nothing left to compress
+    assert!(continue_run_with_store(
+        Some(13),
+        7,
+        &mut store,
+        "room1",
+        &chunk_stats_2.new_level_info,
+        true,
+    )
+    .unwrap()
+    .is_none());
+}
+
+#[test]
+fn verify_committed_reports_groups_changed_after_sending() {
+    let old_map = line_with_state();
+
+    let mut store = MemoryStore::new();
+    store.add_room("room1", &old_map);
+
+    // Turn group 3 into a snapshot (which doesn't change the state of any group)
+    let mut new_map = old_map.clone();
+    new_map.insert(
+        3,
+        StateGroupEntry {
+            in_range: true,
+            prev_state_group: None,
+            state_map: collapse_state_maps(&old_map, 3).unwrap(),
+        },
+    );
+
+    store.send_changes("room1", &old_map, &new_map).unwrap();
+    assert!(verify_committed(&mut store, &old_map, &new_map).is_ok());
+
+    // Now something else changes the delta for group 3 after it was written
+    let mut interfered = new_map.clone();
+    interfered
+        .get_mut(&3)
+        .unwrap()
+        .state_map
+        .insert("node", "is", "tampered".into());
+    store.add_room("room1", &interfered);
+
+    match verify_committed(&mut store, &old_map, &new_map) {
+        Err(CompressorError::CommittedStateMismatch { state_groups }) => {
+            assert_eq!(state_groups, vec![3])
+        }
+        other => panic!("expected a CommittedStateMismatch, got {:?}", other),
+    }
+}
+
+#[test]
+fn committed_state_mismatch_only_shows_first_few_groups() {
+    let error = CompressorError::CommittedStateMismatch {
+        state_groups: (0..25).collect(),
+    };
+    let displayed = error.to_string();
+
+    assert_eq!(
+        displayed.lines().next(),
+        Some("States for 25 groups do not match after committing: [0, 1, 2, 3, 4, 5, 6, 7, 8, 9]")
+    );
+    assert_eq!(displayed.lines().last(), Some("... and 15 more"));
+}
@@ -0,0 +1,217 @@
+//! The TLS options used when connecting to the database.
+//!
+//! These follow the `sslmode`, `sslrootcert`, `sslcert` and `sslkey` options
+//! of libpq, so that the compressor can be pointed at a Postgres server that
+//! requires certificates to be checked. They are shared by this crate and the
+//! synapse_auto_compressor.
+
+use clap::{Arg, ArgMatches};
+use openssl::ssl::{SslConnector, SslFiletype, SslMethod, SslVerifyMode};
+use postgres::config::SslMode as PgSslMode;
+use postgres_openssl::MakeTlsConnector;
+use std::str::FromStr;
+
+use crate::CompressorError;
+
+/// The values accepted for the `sslmode` option
+pub const SSL_MODES: &[&str] = &["disable", "prefer", "require", "verify-ca", "verify-full"];
+
+/// Whether TLS is used for the connection, and how much the server's
+/// certificate is trusted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SslMode {
+    /// Never use TLS
+    Disable,
+    /// Use TLS if the server supports it, but don't check its certificate
+    Prefer,
+    /// Always use TLS, but don't check the server's certificate
+    Require,
+    /// Always use TLS, and check that the server's certificate is signed by
+    /// a trusted CA
+    VerifyCa,
+    /// As `VerifyCa`, but also check that the certificate is for the host
+    /// being connected to
+    VerifyFull,
+}
+
+impl FromStr for SslMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "disable" => Ok(SslMode::Disable),
+            "prefer" => Ok(SslMode::Prefer),
+            "require" => Ok(SslMode::Require),
+            "verify-ca" => Ok(SslMode::VerifyCa),
+            "verify-full" => Ok(SslMode::VerifyFull),
+            _ => Err(format!(
+                "'{}' is not a valid sslmode (expected one of: {})",
+                s,
+                SSL_MODES.join(", ")
+            )),
+        }
+    }
+}
+
+/// Contains the TLS options for connecting to the database
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TlsConfig {
+    // How TLS should be used. If this is None then the sslmode given in the
+    // database URL is used (which defaults to prefer), and the server's
+    // certificate is not checked
+    pub ssl_mode: Option<SslMode>,
+    // A file of PEM encoded CA certificates to trust when checking the
+    // server's certificate. The system's CA certificates are used if this
+    // isn't given
+    pub ca_file: Option<String>,
+    // A PEM encoded certificate (chain) to present to the server
+    pub client_cert: Option<String>,
+    // The PEM encoded private key for client_cert
+    pub client_key: Option<String>,
+}
+
+impl TlsConfig {
+    /// Converts string arguments into a TlsConfig struct
+    ///
+    /// Returns a `Config` error if the sslmode isn't recognised or if only
+    /// one of the client certificate and key were given
+    ///
+    /// # Arguments
+    ///
+    /// * `ssl_mode`        -   One of the values in `SSL_MODES`
+    /// * `ca_file`         -   The CA bundle used to check the server's certificate
+    /// * `client_cert`     -   The client certificate to present to the server
+    /// * `client_key`      -   The private key for the client certificate
+    pub fn new(
+        ssl_mode: Option<&str>,
+        ca_file: Option<String>,
+        client_cert: Option<String>,
+        client_key: Option<String>,
+    ) -> Result<TlsConfig, CompressorError> {
+        let ssl_mode = match ssl_mode {
+            Some(mode) => Some(mode.parse().map_err(CompressorError::Config)?),
+            None => None,
+        };
+
+        if client_cert.is_some() != client_key.is_some() {
+            return Err(CompressorError::Config(
+                "A client certificate and key must be given together".to_string(),
+            ));
+        }
+
+        Ok(TlsConfig {
+            ssl_mode,
+            ca_file,
+            client_cert,
+            client_key,
+        })
+    }
+
+    /// Builds a TlsConfig from the arguments added by `tls_args`
+    pub fn from_matches(matches: &ArgMatches) -> Result<TlsConfig, CompressorError> {
+        TlsConfig::new(
+            matches.value_of("sslmode"),
+            matches.value_of("sslrootcert").map(String::from),
+            matches.value_of("sslcert").map(String::from),
+            matches.value_of("sslkey").map(String::from),
+        )
+    }
+
+    /// Overrides the sslmode in a postgres config, if one was given
+    ///
+    /// Postgres itself only knows about disable, prefer and require, the
+    /// verify modes are enforced by the connector from `make_connector`
+    pub(crate) fn apply_ssl_mode(&self, config: &mut postgres::Config) {
+        let ssl_mode = match self.ssl_mode {
+            Some(SslMode::Disable) => PgSslMode::Disable,
+            Some(SslMode::Prefer) => PgSslMode::Prefer,
+            Some(SslMode::Require) | Some(SslMode::VerifyCa) | Some(SslMode::VerifyFull) => {
+                PgSslMode::Require
+            }
+            None => return,
+        };
+        config.ssl_mode(ssl_mode);
+    }
+
+    /// Builds the connector used to set up TLS for the connection
+    pub(crate) fn make_connector(&self) -> Result<MakeTlsConnector, CompressorError> {
+        let mut builder = SslConnector::builder(SslMethod::tls())?;
+
+        match self.ssl_mode {
+            Some(SslMode::VerifyCa) | Some(SslMode::VerifyFull) => {
+                builder.set_verify(SslVerifyMode::PEER)
+            }
+            _ => builder.set_verify(SslVerifyMode::NONE),
+        }
+
+        if let Some(ca_file) = &self.ca_file {
+            builder.set_ca_file(ca_file)?;
+        }
+
+        if let (Some(cert), Some(key)) = (&self.client_cert, &self.client_key) {
+            builder.set_certificate_chain_file(cert)?;
+            builder.set_private_key_file(key, SslFiletype::PEM)?;
+            builder.check_private_key()?;
+        }
+
+        let mut connector = MakeTlsConnector::new(builder.build());
+
+        // verify-ca trusts any certificate signed by the CA, whatever host it
+        // was issued for
+        if self.ssl_mode == Some(SslMode::VerifyCa) {
+            connector.set_callback(|connect_config, _domain| {
+                connect_config.set_verify_hostname(false);
+                Ok(())
+            });
+        }
+
+        Ok(connector)
+    }
+}
+
+/// The command line arguments used to build a TlsConfig
+pub fn tls_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("sslmode")
+            .long("sslmode")
+            .value_name("MODE")
+            .help("Whether to use TLS for the database connection, and how to check the server")
+            .long_help(concat!(
+                "Whether to use TLS for the database connection. disable, prefer and require",
+                " don't check the server's certificate. verify-ca checks that the certificate",
+                " is signed by a trusted CA and verify-full also checks that it is for the",
+                " host being connected to. If not given then the sslmode in the database URL",
+                " is used."
+            ))
+            .possible_values(SSL_MODES)
+            .takes_value(true)
+            .required(false),
+        Arg::with_name("sslrootcert")
+            .long("sslrootcert")
+            .value_name("FILE")
+            .help("A PEM file of CA certificates used to check the server's certificate")
+            .long_help(concat!(
+                "A PEM file of CA certificates used to check the server's certificate.",
+                " The system's CA certificates are used if this isn't given."
+            ))
+            .takes_value(true)
+            .required(false),
+        Arg::with_name("sslcert")
+            .long("sslcert")
+            .value_name("FILE")
+            .help("A PEM client certificate to present to the server")
+            .takes_value(true)
+            .requires("sslkey")
+            .required(false),
+        Arg::with_name("sslkey")
+            .long("sslkey")
+            .value_name("FILE")
+            .help("The PEM private key for the client certificate")
+            .takes_value(true)
+            .requires("sslcert")
+            .required(false),
+    ]
+}
+
+#[cfg(test)]
+mod tls_tests;
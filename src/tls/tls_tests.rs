@@ -0,0 +1,53 @@
+use crate::{
+    tls::{SslMode, TlsConfig, SSL_MODES},
+    CompressorError,
+};
+
+#[test]
+fn every_ssl_mode_can_be_parsed() {
+    let modes: Vec<SslMode> = SSL_MODES.iter().map(|m| m.parse().unwrap()).collect();
+
+    assert_eq!(
+        modes,
+        vec![
+            SslMode::Disable,
+            SslMode::Prefer,
+            SslMode::Require,
+            SslMode::VerifyCa,
+            SslMode::VerifyFull,
+        ]
+    );
+}
+
+#[test]
+fn new_errors_if_ssl_mode_invalid() {
+    assert!(matches!(
+        TlsConfig::new(Some("allow"), None, None, None),
+        Err(CompressorError::Config(_))
+    ));
+}
+
+#[test]
+fn new_errors_if_only_cert_or_key_given() {
+    assert!(matches!(
+        TlsConfig::new(None, None, Some("client.pem".to_string()), None),
+        Err(CompressorError::Config(_))
+    ));
+    assert!(matches!(
+        TlsConfig::new(None, None, None, Some("client.key".to_string())),
+        Err(CompressorError::Config(_))
+    ));
+}
+
+#[test]
+fn make_connector_errors_if_ca_file_missing() {
+    let tls = TlsConfig::new(
+        Some("verify-full"),
+        Some("/this/file/does/not/exist.pem".to_string()),
+        None,
+        None,
+    )
+    .unwrap();
+
+    assert!(matches!(tls.make_connector(), Err(CompressorError::Tls(_))));
+}
@@ -0,0 +1,421 @@
+// This module contains the loop used when the compressor is run as a daemon,
+// and the schedules that it can use to decide when to run.
+
+use crate::{
+    manager::compress_chunks_of_database_with_workers, metrics::METRICS, room_filter::RoomFilter,
+    room_selection::RoomSelector, state_saving::connect_to_database,
+};
+use anyhow::{Context, Result};
+use log::{error, info, warn};
+use postgres::Client;
+use std::{
+    str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use synapse_compress_state::{Level, TlsConfig};
+
+/// How long to wait between passes if no schedule is given
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// After a pass that found nothing to compress (or that failed), the daemon
+/// skips the next scheduled pass, then the next 3 after the next such pass,
+/// and so on up to 2^MAX_BACKOFF_SHIFT - 1 scheduled passes
+const MAX_BACKOFF_SHIFT: u32 = 4;
+
+/// How often a sleeping daemon checks whether it has been asked to stop
+const STOP_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// When the daemon should run a pass of the compressor
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Schedule {
+    /// Wait this long after one pass finishes before starting the next
+    Interval(Duration),
+    /// Start passes at the times matching a cron expression
+    Cron(CronSchedule),
+}
+
+impl Schedule {
+    /// Works out when the pass after one that finished at `after` should start
+    ///
+    /// Returns None if the schedule never matches again
+    ///
+    /// # Arguments
+    ///
+    /// * `after`           -   When the last pass finished
+    ///
+    /// * `idle_passes`     -   How many passes in a row have found nothing to compress.
+    ///                         The wait is doubled for each of these (by skipping
+    ///                         scheduled passes), up to 2^MAX_BACKOFF_SHIFT times
+    pub fn next_run(&self, after: SystemTime, idle_passes: u32) -> Option<SystemTime> {
+        let passes = 1u32 << idle_passes.min(MAX_BACKOFF_SHIFT);
+        match self {
+            Schedule::Interval(interval) => Some(after + *interval * passes),
+            Schedule::Cron(cron) => {
+                let mut next_run = after;
+                for _ in 0..passes {
+                    next_run = cron.next_after(next_run)?;
+                }
+                Some(next_run)
+            }
+        }
+    }
+
+    /// Whether the first pass should be run as soon as the daemon starts
+    /// (rather than waiting for the first time the schedule matches)
+    fn runs_immediately(&self) -> bool {
+        matches!(self, Schedule::Interval(_))
+    }
+}
+
+/// A cron expression such as "0 3 * * *" (every day at 03:00) or "*/15 * * * 1-5"
+/// (every 15 minutes on weekdays). Times are in UTC.
+///
+/// The five fields are the minute (0-59), hour (0-23), day of the month (1-31),
+/// month (1-12) and day of the week (0-7, where both 0 and 7 are Sunday). Each
+/// field is a comma separated list of `*`, a number or a range such as `1-5`,
+/// optionally followed by a step such as `/15`. As in cron, if both the day of
+/// the month and the day of the week are restricted then a day matching either
+/// of them matches. If either of them starts with `*` (including a step such as
+/// `*/2`) then a day has to match both.
+///
+/// `@hourly`, `@daily`, `@weekly` and `@monthly` can be used as shorthands.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CronSchedule {
+    /// Each of these has the bit for each matching value set
+    minutes: u64,
+    hours: u64,
+    days_of_month: u64,
+    months: u64,
+    days_of_week: u64,
+    /// Whether the day fields start with `*`, in which case a day has to match
+    /// both of them rather than either
+    any_day_of_month: bool,
+    any_day_of_week: bool,
+}
+
+impl FromStr for CronSchedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let expression = match s.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            expression => expression,
+        };
+
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(format!(
+                "'{}' should have 5 fields: minute hour day-of-month month day-of-week",
+                s
+            ));
+        }
+
+        let mut days_of_week = parse_cron_field(fields[4], 0, 7)?;
+        // 7 is another way of writing Sunday
+        if days_of_week & (1 << 7) != 0 {
+            days_of_week = (days_of_week | 1) & !(1 << 7);
+        }
+
+        let schedule = CronSchedule {
+            minutes: parse_cron_field(fields[0], 0, 59)?,
+            hours: parse_cron_field(fields[1], 0, 23)?,
+            days_of_month: parse_cron_field(fields[2], 1, 31)?,
+            months: parse_cron_field(fields[3], 1, 12)?,
+            days_of_week,
+            any_day_of_month: fields[2].starts_with('*'),
+            any_day_of_week: fields[4].starts_with('*'),
+        };
+
+        // Catch things like "0 0 31 2 *" that will never run
+        if schedule.next_after(UNIX_EPOCH).is_none() {
+            return Err(format!("'{}' never matches any time", s));
+        }
+
+        Ok(schedule)
+    }
+}
+
+/// Parses one field of a cron expression into a bitmask of the values it matches
+fn parse_cron_field(field: &str, min: u32, max: u32) -> Result<u64, String> {
+    let parse_value = |value: &str| -> Result<u32, String> {
+        value
+            .parse()
+            .map_err(|_| format!("'{}' in '{}' is not a number", value, field))
+    };
+
+    let mut bits = 0u64;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, Some(parse_value(step)?)),
+            None => (part, None),
+        };
+
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((start, end)) = range.split_once('-') {
+            (parse_value(start)?, parse_value(end)?)
+        } else {
+            // "5/15" means every 15 starting from 5
+            let start = parse_value(range)?;
+            (start, if step.is_some() { max } else { start })
+        };
+
+        if start < min || end > max || start > end {
+            return Err(format!(
+                "'{}' is outside of the range {}-{}",
+                range, min, max
+            ));
+        }
+
+        let step = match step {
+            Some(0) => return Err(format!("The step in '{}' can't be 0", part)),
+            Some(step) => step as usize,
+            None => 1,
+        };
+
+        for value in (start..=end).step_by(step) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+impl CronSchedule {
+    /// Finds the first minute after `after` that matches this schedule
+    ///
+    /// Returns None if there isn't one in the next 8 years (which covers
+    /// schedules that only match on the 29th of February)
+    pub fn next_after(&self, after: SystemTime) -> Option<SystemTime> {
+        let after = after.duration_since(UNIX_EPOCH).ok()?.as_secs();
+        let first_minute = after / 60 + 1;
+        let first_day = first_minute / MINUTES_IN_DAY;
+
+        for day in first_day..first_day + 8 * 366 {
+            if !self.matches_day(day) {
+                continue;
+            }
+
+            let first_minute_of_day = if day == first_day {
+                first_minute % MINUTES_IN_DAY
+            } else {
+                0
+            };
+            for minute_of_day in first_minute_of_day..MINUTES_IN_DAY {
+                let (hour, minute) = (minute_of_day / 60, minute_of_day % 60);
+                if self.hours & (1 << hour) != 0 && self.minutes & (1 << minute) != 0 {
+                    let minutes = day * MINUTES_IN_DAY + minute_of_day;
+                    return Some(UNIX_EPOCH + Duration::from_secs(minutes * 60));
+                }
+            }
+        }
+        None
+    }
+
+    /// Whether the day this many days after the unix epoch matches
+    fn matches_day(&self, day: u64) -> bool {
+        let (_, month, day_of_month) = date_from_days(day);
+        // The epoch was a Thursday
+        let day_of_week = (day + 4) % 7;
+
+        if self.months & (1 << month) == 0 {
+            return false;
+        }
+
+        let matches_day_of_month = self.days_of_month & (1 << day_of_month) != 0;
+        let matches_day_of_week = self.days_of_week & (1 << day_of_week) != 0;
+        // A plain `*` has every bit set, so only restricts the day through the
+        // other field
+        if self.any_day_of_month || self.any_day_of_week {
+            matches_day_of_month && matches_day_of_week
+        } else {
+            matches_day_of_month || matches_day_of_week
+        }
+    }
+}
+
+const MINUTES_IN_DAY: u64 = 24 * 60;
+
+/// Converts a number of days since the unix epoch into the (year, month, day of the month)
+/// of that date, using the algorithm from http://howardhinnant.github.io/date_algorithms.html
+pub(crate) fn date_from_days(days: u64) -> (u64, u64, u64) {
+    // Shift the epoch to 0000-03-01 so that leap days are at the end of the year
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day_of_month = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    };
+    // Years start in March, so January and February are in the next one
+    let year = era * 400 + year_of_era + if month <= 2 { 1 } else { 0 };
+    (year, month, day_of_month)
+}
+
+/// Runs passes of the compressor according to a schedule until `stop` is set
+///
+/// Each pass is a call to `compress_chunks_of_database_with_workers`. If a pass finds
+/// nothing to compress then the daemon backs off by skipping scheduled passes (see
+/// `Schedule::next_run`), and goes back to the schedule once there is work to do.
+///
+/// If a pass fails then the error is logged, the clients are connected to the database
+/// again and the daemon backs off in the same way until a pass succeeds.
+///
+/// When `stop` is set the chunks being compressed are finished, and their progress
+/// saved, before this returns. It only returns an error if the schedule never runs
+/// again.
+///
+/// # Arguments
+///
+/// * `clients`         -   Connections to the postgres database that synapse is using,
+///                         one for each worker
+///
+/// * `db_url`          -   The URL of the database, used to reconnect after a pass fails
+///
+/// * `tls`             -   How to use TLS when reconnecting
+///
+/// * `chunk_size`      -   The number of state_groups to work on (see
+///                         `compress_chunks_of_database`)
+///
+/// * `default_levels`  -   The levels to use for rooms that haven't been compressed before
+///
+/// * `number_of_chunks`-   The number of chunks to compress in each pass
+///
+/// * `room_selector`   -   Decides which room to compress a chunk of next
+///
+/// * `room_filter`     -   Which rooms may be compressed
+///
+/// * `max_duration`    -   How long each pass should take at most (see
+///                         `compress_chunks_of_database`)
+///
+/// * `schedule`        -   When to run the passes
+///
+/// * `stop`            -   Set (e.g. by a signal handler) to make the daemon exit
+///
+/// * `atomic`          -   Whether to make the changes to each chunk in a single transaction
+///                         (see `run_compressor_on_room_chunk`)
+///
+/// * `verify_committed`-   Whether to check the changed state groups against the database
+///                         once they have been written (see `run_compressor_on_room_chunk`)
+#[allow(clippy::too_many_arguments)]
+pub fn run_daemon(
+    clients: &mut [Client],
+    db_url: &str,
+    tls: &TlsConfig,
+    chunk_size: i64,
+    default_levels: &[Level],
+    number_of_chunks: i64,
+    room_selector: &dyn RoomSelector,
+    room_filter: &RoomFilter,
+    max_duration: Option<Duration>,
+    schedule: &Schedule,
+    stop: &AtomicBool,
+    atomic: bool,
+    verify_committed: bool,
+) -> Result<()> {
+    info!("Running as a daemon with schedule {:?}", schedule);
+
+    if !schedule.runs_immediately() {
+        wait_for_next_run(schedule, 0, stop)?;
+    }
+
+    let mut idle_passes = 0;
+    let mut failed_passes = 0;
+    while !stop.load(Ordering::SeqCst) {
+        info!("Starting a pass of the compressor");
+        let run_result = compress_chunks_of_database_with_workers(
+            clients,
+            chunk_size,
+            default_levels,
+            number_of_chunks,
+            room_selector,
+            room_filter,
+            max_duration,
+            Some(stop),
+            atomic,
+            verify_committed,
+        );
+
+        match run_result {
+            Ok(run_stats) => {
+                failed_passes = 0;
+                if run_stats.chunks_processed == 0 && run_stats.out_of_rooms {
+                    idle_passes += 1;
+                } else {
+                    idle_passes = 0;
+                }
+            }
+            Err(e) => {
+                failed_passes += 1;
+                METRICS.record_failed_pass();
+                error!("Pass of the compressor failed: {:?}", e);
+                reconnect(clients, db_url, tls);
+            }
+        }
+
+        let backoff = if failed_passes > 0 {
+            info!("The last {} passes failed, backing off", failed_passes);
+            failed_passes
+        } else {
+            if idle_passes > 0 {
+                info!(
+                    "Found nothing to compress in the last {} passes, backing off",
+                    idle_passes
+                );
+            }
+            idle_passes
+        };
+
+        wait_for_next_run(schedule, backoff, stop)?;
+    }
+
+    info!("Stopping the daemon");
+    Ok(())
+}
+
+/// Replaces each of the clients with a new connection to the database, so that a
+/// pass that failed because a connection was lost can be retried. Clients that
+/// can't be reconnected are kept, and tried again if the next pass fails too.
+fn reconnect(clients: &mut [Client], db_url: &str, tls: &TlsConfig) {
+    for client in clients.iter_mut() {
+        match connect_to_database(db_url, tls) {
+            Ok(new_client) => *client = new_client,
+            Err(e) => warn!("Failed to reconnect to the database: {:?}", e),
+        }
+    }
+}
+
+/// Sleeps until the next pass is due, or until `stop` is set
+///
+/// `backoff` is how many passes in a row found nothing to do or failed (see
+/// `Schedule::next_run`)
+fn wait_for_next_run(schedule: &Schedule, backoff: u32, stop: &AtomicBool) -> Result<()> {
+    let next_run = schedule
+        .next_run(SystemTime::now(), backoff)
+        .context("The schedule never runs again")?;
+
+    if let Ok(wait) = next_run.duration_since(SystemTime::now()) {
+        info!(
+            "Sleeping for {:?} until the next pass",
+            Duration::from_secs(wait.as_secs())
+        );
+    }
+
+    while !stop.load(Ordering::SeqCst) {
+        match next_run.duration_since(SystemTime::now()) {
+            Ok(wait) if !wait.is_zero() => thread::sleep(wait.min(STOP_CHECK_INTERVAL)),
+            _ => break,
+        }
+    }
+    Ok(())
+}
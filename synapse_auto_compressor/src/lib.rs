@@ -6,16 +6,22 @@
 //! to the database and uses these to enable it to incrementally work
 //! on space reductions
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use log::{error, LevelFilter};
 use pyo3::{
     exceptions::PyRuntimeError, prelude::pymodule, types::PyModule, PyErr, PyResult, Python,
 };
-use std::str::FromStr;
+use room_filter::RoomFilter;
+use room_selection::RoomSelection;
+use std::{str::FromStr, time::Duration};
 
-use synapse_compress_state::Level;
+use synapse_compress_state::{py_errors, CompressorError, Level, TlsConfig};
 
+pub mod daemon;
 pub mod manager;
+pub mod metrics;
+pub mod room_filter;
+pub mod room_selection;
 pub mod state_saving;
 
 /// Helper struct for parsing the `default_levels` argument.
@@ -55,9 +61,52 @@ impl FromStr for LevelInfo {
     }
 }
 
+/// Parses a duration such as "90s", "30m" or "1.5h" (a number without a unit is
+/// in seconds)
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let (number, unit_secs) = match s.char_indices().last() {
+        Some((i, 's')) => (&s[..i], 1.0),
+        Some((i, 'm')) => (&s[..i], 60.0),
+        Some((i, 'h')) => (&s[..i], 60.0 * 60.0),
+        _ => (s, 1.0),
+    };
+
+    let number: f64 = number
+        .trim()
+        .parse()
+        .map_err(|_| format!("'{}' is not a duration such as 90s, 30m or 2h", s))?;
+    duration_from_secs(number * unit_secs)
+}
+
+/// Formats a number of milliseconds since the unix epoch (as stored in the database)
+/// as a UTC time such as "2024-02-28 12:34:56"
+pub fn format_timestamp(ts: i64) -> String {
+    let secs = ts.div_euclid(1000).max(0) as u64;
+    let (year, month, day) = daemon::date_from_days(secs / 86400);
+    let secs_of_day = secs % 86400;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60
+    )
+}
+
+/// Converts a number of seconds into a duration, checking that it isn't negative
+fn duration_from_secs(secs: f64) -> Result<Duration, String> {
+    if !secs.is_finite() || secs < 0.0 {
+        return Err(format!("{} is not a valid number of seconds", secs));
+    }
+    Ok(Duration::from_secs_f64(secs))
+}
+
 // PyO3 INTERFACE STARTS HERE
 #[pymodule]
-fn synapse_auto_compressor(_py: Python, m: &PyModule) -> PyResult<()> {
+fn synapse_auto_compressor(py: Python, m: &PyModule) -> PyResult<()> {
     let _ = pyo3_log::Logger::default()
         // don't send out anything lower than a warning from other crates
         .filter(LevelFilter::Warn)
@@ -71,26 +120,71 @@ fn synapse_auto_compressor(_py: Python, m: &PyModule) -> PyResult<()> {
     // ensure any panics produce error messages in the log
     log_panics::init();
 
-    #[pyfn(m, compress_largest_rooms)]
+    // make the compressor's exception types available to python
+    py_errors::add_to_module(py, m)?;
+
+    #[allow(clippy::too_many_arguments)]
+    #[pyfn(
+        m,
+        compress_largest_rooms,
+        max_duration = "None",
+        room_selection = "String::from(\"lowest-id\")",
+        include_rooms = "None",
+        exclude_rooms = "None",
+        ssl_mode = "None",
+        ssl_ca_file = "None",
+        ssl_cert = "None",
+        ssl_key = "None"
+    )]
     fn compress_state_events_table(
         py: Python,
         db_url: String,
         chunk_size: i64,
         default_levels: String,
         number_of_chunks: i64,
+        max_duration: Option<f64>,
+        room_selection: String,
+        include_rooms: Option<Vec<String>>,
+        exclude_rooms: Option<Vec<String>>,
+        ssl_mode: Option<String>,
+        ssl_ca_file: Option<String>,
+        ssl_cert: Option<String>,
+        ssl_key: Option<String>,
     ) -> PyResult<()> {
         // Stops the compressor from holding the GIL while running
         py.allow_threads(|| {
-            _compress_state_events_table_body(db_url, chunk_size, default_levels, number_of_chunks)
+            _compress_state_events_table_body(
+                db_url,
+                chunk_size,
+                default_levels,
+                number_of_chunks,
+                max_duration,
+                room_selection,
+                include_rooms,
+                exclude_rooms,
+                ssl_mode,
+                ssl_ca_file,
+                ssl_cert,
+                ssl_key,
+            )
         })
     }
 
     // Not accessbile through Py03. It is a "private" function.
+    #[allow(clippy::too_many_arguments)]
     fn _compress_state_events_table_body(
         db_url: String,
         chunk_size: i64,
         default_levels: String,
         number_of_chunks: i64,
+        max_duration: Option<f64>,
+        room_selection: String,
+        include_rooms: Option<Vec<String>>,
+        exclude_rooms: Option<Vec<String>>,
+        ssl_mode: Option<String>,
+        ssl_ca_file: Option<String>,
+        ssl_cert: Option<String>,
+        ssl_key: Option<String>,
     ) -> PyResult<()> {
         // Announce the start of the program to the logs
         log::info!("synapse_auto_compressor started");
@@ -106,19 +200,71 @@ fn synapse_auto_compressor(_py: Python, m: &PyModule) -> PyResult<()> {
             }
         };
 
-        // call compress_largest_rooms with the arguments supplied
-        let run_result = manager::compress_chunks_of_database(
-            &db_url,
-            chunk_size,
-            &default_levels.0,
-            number_of_chunks,
-        );
+        // max_duration is in seconds
+        let max_duration = match max_duration.map(duration_from_secs).transpose() {
+            Ok(max_duration) => max_duration,
+            Err(e) => {
+                return Err(PyErr::new::<PyRuntimeError, _>(format!(
+                    "Unable to parse max_duration: {}",
+                    e
+                )))
+            }
+        };
+
+        let room_selection: RoomSelection = match room_selection.parse() {
+            Ok(room_selection) => room_selection,
+            Err(e) => {
+                return Err(PyErr::new::<PyRuntimeError, _>(format!(
+                    "Unable to parse room_selection: {}",
+                    e
+                )))
+            }
+        };
+
+        let room_filter = match RoomFilter::new(
+            include_rooms.unwrap_or_default(),
+            exclude_rooms.unwrap_or_default(),
+        ) {
+            Ok(room_filter) => room_filter,
+            Err(e) => {
+                return Err(PyErr::new::<PyRuntimeError, _>(format!(
+                    "Unable to parse include_rooms or exclude_rooms: {}",
+                    e
+                )))
+            }
+        };
+
+        // How TLS should be used when connecting to the database
+        let tls = TlsConfig::new(ssl_mode.as_deref(), ssl_ca_file, ssl_cert, ssl_key)?;
+
+        // connect to the database once, this connection is used for every chunk
+        // and call compress_largest_rooms with the arguments supplied
+        let run_result = state_saving::connect_to_database(&db_url, &tls)
+            .with_context(|| format!("Failed to connect to database at {}", db_url))
+            .and_then(|mut client| {
+                manager::compress_chunks_of_database(
+                    &mut client,
+                    chunk_size,
+                    &default_levels.0,
+                    number_of_chunks,
+                    room_selection.selector(),
+                    &room_filter,
+                    max_duration,
+                    false,
+                    false,
+                )
+            });
 
         // (Note, need to do `{:?}` formatting to show error context)
-        // Don't log the context of errors but do use it in the PyRuntimeError
+        // Don't log the context of errors but do use it in the python exception
         if let Err(e) = run_result {
             error!("{}", e);
-            return Err(PyErr::new::<PyRuntimeError, _>(format!("{:?}", e)));
+            // Errors from the compressor itself are raised as the matching
+            // python exception, anything else is a PyRuntimeError
+            return Err(match e.downcast_ref::<CompressorError>() {
+                Some(compressor_error) => py_errors::new_err(compressor_error, format!("{:?}", e)),
+                None => PyErr::new::<PyRuntimeError, _>(format!("{:?}", e)),
+            });
         }
 
         log::info!("synapse_auto_compressor finished");
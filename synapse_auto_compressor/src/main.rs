@@ -15,14 +15,40 @@
 //! the state_compressor_progress table), and the program state is saved into
 //! the state_compressor_state table so that the compressor can seemlesly
 //! continue from where it left off.
+//!
+//! With --daemon this is repeated on a schedule until the process is sent
+//! SIGTERM or SIGINT.
 
 #[global_allocator]
 static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
 
-use clap::{crate_authors, crate_description, crate_name, crate_version, value_t, App, Arg};
+use clap::{
+    crate_authors, crate_description, crate_name, crate_version, value_t, App, AppSettings, Arg,
+    ArgMatches, SubCommand,
+};
 use log::LevelFilter;
-use std::env;
-use synapse_auto_compressor::{manager, state_saving, LevelInfo};
+use signal_hook::{
+    consts::{SIGINT, SIGTERM},
+    flag,
+};
+use std::{
+    env,
+    path::PathBuf,
+    sync::{atomic::AtomicBool, Arc},
+    time::Duration,
+};
+use synapse_auto_compressor::{
+    daemon::{self, Schedule},
+    format_timestamp, manager, metrics, parse_duration,
+    room_filter::{read_room_list, RoomFilter},
+    room_selection::{RoomSelection, ROOM_SELECTIONS},
+    state_saving::{self, ChunkHistory, HistorySummary},
+    LevelInfo,
+};
+use synapse_compress_state::{tls_args, TlsConfig};
+
+/// How often the file given by --metrics-file is rewritten
+const METRICS_FILE_INTERVAL: Duration = Duration::from_secs(15);
 
 /// Execution starts here
 fn main() {
@@ -54,6 +80,7 @@ fn main() {
         .version(crate_version!())
         .author(crate_authors!("\n"))
         .about(crate_description!())
+        .setting(AppSettings::SubcommandsNegateReqs)
         .arg(
             Arg::with_name("postgres-url")
                 .short("p")
@@ -113,7 +140,229 @@ fn main() {
                 ))
                 .takes_value(true)
                 .required(true),
-        ).get_matches();
+        ).arg(
+            Arg::with_name("room_selection")
+                .long("room-selection")
+                .value_name("STRATEGY")
+                .help("How to pick the room to compress a chunk of next")
+                .long_help(concat!(
+                    "How to pick the room to compress a chunk of next. 'lowest-id' picks the room",
+                    " with the lowest uncompressed state group, working through the rooms in the",
+                    " order they were created. 'largest' picks the room with the most rows in",
+                    " state_groups_state. 'most-uncompressed' picks the room with the most state",
+                    " groups that haven't been compressed. 'estimated-savings' picks the room with",
+                    " the most rows in its uncompressed state groups, less one for each group.",
+                    " 'largest' and 'estimated-savings' count the rows of every room they rank,",
+                    " which is slow on large databases, so they only rank the rooms once per run",
+                    " (or per pass with --daemon). 'most-uncompressed' ranks them again every 100",
+                    " chunks.",
+                ))
+                .possible_values(ROOM_SELECTIONS)
+                .default_value("lowest-id")
+                .takes_value(true)
+                .required(false),
+        ).arg(
+            Arg::with_name("include_rooms")
+                .long("include-rooms")
+                .value_name("ROOMS")
+                .help("Only compress these rooms (room IDs or server name globs)")
+                .long_help(concat!(
+                    "Only compress rooms that match one of these comma separated patterns. A pattern",
+                    " is either a room ID, such as !abc:example.com, or a glob matching the server",
+                    " name part of room IDs, such as example.com or *.example.com (where * matches",
+                    " any characters and ? matches one). Can be given more than once.",
+                ))
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .use_delimiter(true)
+                .required(false),
+        ).arg(
+            Arg::with_name("include_rooms_file")
+                .long("include-rooms-file")
+                .value_name("FILE")
+                .help("Only compress the rooms listed in FILE")
+                .long_help(concat!(
+                    "Only compress rooms that match one of the patterns in FILE (see --include-rooms),",
+                    " which has one pattern on each line. Blank lines and anything after a # are ignored.",
+                ))
+                .takes_value(true)
+                .required(false),
+        ).arg(
+            Arg::with_name("exclude_rooms")
+                .long("exclude-rooms")
+                .value_name("ROOMS")
+                .help("Never compress these rooms (room IDs or server name globs)")
+                .long_help(concat!(
+                    "Never compress rooms that match one of these comma separated patterns (see",
+                    " --include-rooms). This takes priority over the rooms to include. Can be given",
+                    " more than once.",
+                ))
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .use_delimiter(true)
+                .required(false),
+        ).arg(
+            Arg::with_name("exclude_rooms_file")
+                .long("exclude-rooms-file")
+                .value_name("FILE")
+                .help("Never compress the rooms listed in FILE")
+                .long_help(concat!(
+                    "Never compress rooms that match one of the patterns in FILE (see --include-rooms),",
+                    " which has one pattern on each line. Blank lines and anything after a # are ignored.",
+                ))
+                .takes_value(true)
+                .required(false),
+        ).arg(
+            Arg::with_name("max_duration")
+                .long("max-duration")
+                .value_name("DURATION")
+                .help("Stop starting new chunks once there isn't time to finish one within DURATION")
+                .long_help(concat!(
+                    "Stop starting new chunks once there isn't time to compress one before DURATION",
+                    " has passed, such as 90s, 30m or 2h (a number without a unit is in seconds).",
+                    " How long a chunk takes is estimated from the average of the chunks compressed",
+                    " so far. Chunks that have been started are always finished, so the run can go",
+                    " over by up to a chunk. The run also stops after CHUNKS_TO_COMPRESS chunks.",
+                ))
+                .takes_value(true)
+                .required(false),
+        ).arg(
+            Arg::with_name("atomic")
+                .short("a")
+                .long("atomic")
+                .help("Commit all of the changes to a chunk in a single transaction")
+                .long_help(concat!(
+                    "If this flag is set then all of the changes to a chunk, and the saved",
+                    " compressor state, are made in a single transaction. Before committing,",
+                    " the state of every changed group is read back from the database and",
+                    " compared to its original state. If anything differs then the transaction",
+                    " is rolled back. Without this flag the state groups are committed in small batches.",
+                ))
+                .required(false),
+        ).arg(
+            Arg::with_name("verify_committed")
+                .long("verify-committed")
+                .help("Check the committed state groups against the database")
+                .long_help(concat!(
+                    "If this flag is set then once the changes to a chunk have been written,",
+                    " the changed state groups are read back from the database and compared to",
+                    " the original groups. This catches changes made by anything else while the",
+                    " compressor was writing (such as synapse or a database trigger). The",
+                    " compressor stops and reports the state groups that don't match. This",
+                    " can't be used with --atomic, which already makes the same check but",
+                    " before committing, so that the transaction can be rolled back.",
+                ))
+                .conflicts_with("atomic")
+                .required(false),
+        ).arg(
+            Arg::with_name("workers")
+                .short("w")
+                .long("workers")
+                .value_name("COUNT")
+                .help("The number of rooms to compress at the same time")
+                .long_help(concat!(
+                    "The number of rooms to compress at the same time. Each worker has its own",
+                    " connection to the database and compresses a chunk of a different room, so",
+                    " no two workers ever work on the same room. The chunks are shared between",
+                    " the workers, so -n is still the total number of chunks compressed.",
+                ))
+                .default_value("1")
+                .takes_value(true)
+                .required(false),
+        ).arg(
+            Arg::with_name("daemon")
+                .long("daemon")
+                .help("Keep running, compressing the database on a schedule")
+                .long_help(concat!(
+                    "Keep running, compressing CHUNKS_TO_COMPRESS chunks each time the schedule",
+                    " (--interval or --schedule) says to. If a pass finds nothing to compress then",
+                    " the daemon backs off by skipping scheduled passes. On SIGTERM or SIGINT the",
+                    " chunks being compressed are finished and their progress saved before exiting.",
+                ))
+                .required(false),
+        ).arg(
+            Arg::with_name("interval")
+                .long("interval")
+                .value_name("DURATION")
+                .help("How long the daemon waits between passes [defaults to 1h]")
+                .long_help(concat!(
+                    "How long the daemon waits after one pass finishes before starting the next,",
+                    " such as 90s, 30m or 2h. The first pass is started straight away. [defaults to 1h]",
+                ))
+                .takes_value(true)
+                .requires("daemon")
+                .conflicts_with("schedule")
+                .required(false),
+        ).arg(
+            Arg::with_name("schedule")
+                .long("schedule")
+                .value_name("CRON")
+                .help("A cron expression (in UTC) for when the daemon starts passes")
+                .long_help(concat!(
+                    "A cron expression for when the daemon starts passes, such as \"0 3 * * *\"",
+                    " for 03:00 every day. The fields are minute, hour, day of month, month and",
+                    " day of week, and times are in UTC. @hourly, @daily, @weekly and @monthly",
+                    " can also be used.",
+                ))
+                .takes_value(true)
+                .requires("daemon")
+                .required(false),
+        ).arg(
+            Arg::with_name("metrics_address")
+                .long("metrics-address")
+                .value_name("ADDRESS")
+                .help("Serve Prometheus metrics on /metrics at this address, e.g. 127.0.0.1:9300")
+                .takes_value(true)
+                .required(false),
+        ).arg(
+            Arg::with_name("metrics_file")
+                .long("metrics-file")
+                .value_name("FILE")
+                .help("Write Prometheus metrics to this file for the node exporter's textfile collector")
+                .long_help(concat!(
+                    "Write Prometheus metrics to this file for the node exporter's textfile",
+                    " collector. The file should end in .prom, and is rewritten every 15 seconds",
+                    " and when the compressor exits.",
+                ))
+                .takes_value(true)
+                .required(false),
+        ).args(&tls_args())
+        .subcommand(
+            SubCommand::with_name("history")
+                .about("Show what the compressor has done to each chunk it has been run on")
+                .arg(
+                    Arg::with_name("postgres-url")
+                        .short("p")
+                        .value_name("POSTGRES_LOCATION")
+                        .help("The configruation for connecting to the postgres database.")
+                        .takes_value(true)
+                        .required(true),
+                ).arg(
+                    Arg::with_name("room_id")
+                        .short("r")
+                        .long("room")
+                        .value_name("ROOM_ID")
+                        .help("Only show the history of this room")
+                        .takes_value(true)
+                        .required(false),
+                ).arg(
+                    Arg::with_name("limit")
+                        .long("limit")
+                        .value_name("COUNT")
+                        .help("The number of chunks to show, most recent first")
+                        .default_value("20")
+                        .takes_value(true)
+                        .required(false),
+                ).args(&tls_args()),
+        )
+        .get_matches();
+
+    if let Some(history_arguments) = arguments.subcommand_matches("history") {
+        show_history(history_arguments);
+        return;
+    }
 
     // The URL of the database
     let db_url = arguments
@@ -136,17 +385,237 @@ fn main() {
         .map(|s| s.parse().expect("number_of_chunks must be an integer"))
         .expect("number_of_chunks is required");
 
-    // Connect to the database and create the 2 tables this tool needs
-    // (Note: if they already exist then this does nothing)
-    let mut client = state_saving::connect_to_database(db_url)
+    // How to pick the next room to compress
+    let room_selection = value_t!(arguments, "room_selection", RoomSelection)
+        .unwrap_or_else(|e| panic!("Unable to parse room selection: {}", e));
+
+    // Which rooms may be compressed
+    let room_patterns = |arg_name: &str, file_arg_name: &str| {
+        let mut patterns: Vec<String> = arguments
+            .values_of(arg_name)
+            .map_or_else(Vec::new, |values| values.map(String::from).collect());
+        if let Some(path) = arguments.value_of(file_arg_name) {
+            patterns.extend(read_room_list(path.as_ref()).unwrap_or_else(|e| panic!("{:?}", e)));
+        }
+        patterns
+    };
+    let room_filter = RoomFilter::new(
+        room_patterns("include_rooms", "include_rooms_file"),
+        room_patterns("exclude_rooms", "exclude_rooms_file"),
+    )
+    .unwrap_or_else(|e| panic!("Unable to parse room lists: {}", e));
+
+    // How long the run can take
+    let max_duration = arguments.value_of("max_duration").map(|s| {
+        parse_duration(s).unwrap_or_else(|e| panic!("Unable to parse max_duration: {}", e))
+    });
+
+    // Whether to commit each chunk in a single transaction
+    let atomic = arguments.is_present("atomic");
+
+    // Whether to check the changes against the database once written
+    let verify_committed = arguments.is_present("verify_committed");
+
+    // The number of rooms to compress at the same time
+    let workers: usize = arguments
+        .value_of("workers")
+        .map(|s| s.parse().expect("workers must be an integer"))
+        .expect("workers has a default");
+    if workers == 0 {
+        panic!("workers must be at least 1");
+    }
+
+    // When to run passes of the compressor, if running as a daemon
+    let schedule = if let Some(cron) = arguments.value_of("schedule") {
+        Schedule::Cron(
+            cron.parse()
+                .unwrap_or_else(|e| panic!("Unable to parse schedule: {}", e)),
+        )
+    } else {
+        Schedule::Interval(
+            arguments
+                .value_of("interval")
+                .map_or(daemon::DEFAULT_INTERVAL, |s| {
+                    parse_duration(s).unwrap_or_else(|e| panic!("Unable to parse interval: {}", e))
+                }),
+        )
+    };
+
+    // How TLS should be used when connecting to the database
+    let tls = TlsConfig::from_matches(&arguments)
+        .unwrap_or_else(|e| panic!("Unable to parse TLS options: {}", e));
+
+    // Connect to the database once for each worker and create the 2 tables this
+    // tool needs (Note: if they already exist then this does nothing)
+    let mut clients: Vec<_> = (0..workers)
+        .map(|_| {
+            state_saving::connect_to_database(db_url, &tls)
+                .unwrap_or_else(|e| panic!("Error occured while connecting to {}: {}", db_url, e))
+        })
+        .collect();
+    state_saving::create_tables_if_needed(&mut clients[0])
+        .unwrap_or_else(|e| panic!("Error occured while creating tables in database: {}", e));
+
+    // Export metrics about what the compressor is doing
+    if let Some(address) = arguments.value_of("metrics_address") {
+        let address = metrics::serve(address).unwrap_or_else(|e| panic!("{:?}", e));
+        log::info!("Serving metrics on http://{}/metrics", address);
+    }
+    let metrics_file = arguments.value_of("metrics_file").map(PathBuf::from);
+    if let Some(metrics_file) = &metrics_file {
+        metrics::write_textfile_periodically(metrics_file.clone(), METRICS_FILE_INTERVAL);
+    }
+
+    // On the first SIGTERM or SIGINT finish the chunks being compressed and
+    // then stop. A second one exits straight away.
+    let stop = Arc::new(AtomicBool::new(false));
+    for signal in [SIGTERM, SIGINT] {
+        flag::register_conditional_shutdown(signal, 1, Arc::clone(&stop))
+            .and_then(|_| flag::register(signal, Arc::clone(&stop)))
+            .unwrap_or_else(|e| panic!("Unable to set up signal handlers: {}", e));
+    }
+
+    // call compress_largest_rooms with the arguments supplied, with each worker
+    // reusing its connection made above for every chunk
+    // panic if an error is produced (once the final metrics have been written)
+    let run_result = if arguments.is_present("daemon") {
+        daemon::run_daemon(
+            &mut clients,
+            db_url,
+            &tls,
+            chunk_size,
+            &default_levels.0,
+            number_of_chunks,
+            room_selection.selector(),
+            &room_filter,
+            max_duration,
+            &schedule,
+            &stop,
+            atomic,
+            verify_committed,
+        )
+    } else {
+        manager::compress_chunks_of_database_with_workers(
+            &mut clients,
+            chunk_size,
+            &default_levels.0,
+            number_of_chunks,
+            room_selection.selector(),
+            &room_filter,
+            max_duration,
+            Some(&stop),
+            atomic,
+            verify_committed,
+        )
+        .map(|_| ())
+    };
+
+    if let Some(metrics_file) = &metrics_file {
+        if let Err(e) = metrics::write_textfile(metrics_file) {
+            log::warn!("{:?}", e);
+        }
+    }
+    run_result.unwrap();
+
+    log::info!("synapse_auto_compressor finished");
+}
+
+/// Prints the rows of state_compressor_history asked for by the history subcommand,
+/// followed by the totals for the room (or for every room)
+fn show_history(arguments: &ArgMatches) {
+    let db_url = arguments
+        .value_of("postgres-url")
+        .expect("A database url is required");
+    let room_id = arguments.value_of("room_id");
+    let limit: i64 = arguments
+        .value_of("limit")
+        .map(|s| s.parse().expect("limit must be an integer"))
+        .expect("limit has a default");
+
+    let tls = TlsConfig::from_matches(arguments)
+        .unwrap_or_else(|e| panic!("Unable to parse TLS options: {}", e));
+    let mut client = state_saving::connect_to_database(db_url, &tls)
         .unwrap_or_else(|e| panic!("Error occured while connecting to {}: {}", db_url, e));
     state_saving::create_tables_if_needed(&mut client)
         .unwrap_or_else(|e| panic!("Error occured while creating tables in database: {}", e));
 
-    // call compress_largest_rooms with the arguments supplied
-    // panic if an error is produced
-    manager::compress_chunks_of_database(db_url, chunk_size, &default_levels.0, number_of_chunks)
-        .unwrap();
+    let history = state_saving::read_chunk_history(&mut client, room_id, limit)
+        .unwrap_or_else(|e| panic!("Error occured while reading history: {}", e));
+    let summary = state_saving::read_history_summary(&mut client, room_id)
+        .unwrap_or_else(|e| panic!("Error occured while reading history: {}", e));
 
-    log::info!("synapse_auto_compressor finished");
+    print!("{}", format_history(&history));
+    println!("{}", format_history_summary(&summary));
+}
+
+/// Lays out chunks of history as a table, one chunk per line
+fn format_history(history: &[ChunkHistory]) -> String {
+    let mut table = vec![[
+        "FINISHED (UTC)".to_string(),
+        "ROOM".to_string(),
+        "GROUPS".to_string(),
+        "LEVELS".to_string(),
+        "ROWS BEFORE".to_string(),
+        "ROWS AFTER".to_string(),
+        "STATUS".to_string(),
+        "DURATION".to_string(),
+        "VERSION".to_string(),
+    ]];
+
+    for chunk in history {
+        // The chunk is the groups after start_group, up to and including end_group
+        let start_group = chunk
+            .start_group
+            .map_or_else(|| "-".to_string(), |start_group| start_group.to_string());
+        table.push([
+            format_timestamp(chunk.ts),
+            chunk.room_id.clone(),
+            format!("({}, {}]", start_group, chunk.end_group),
+            chunk.level_sizes.clone(),
+            chunk.original_num_rows.to_string(),
+            chunk.new_num_rows.to_string(),
+            if chunk.committed {
+                "committed".to_string()
+            } else {
+                "skipped".to_string()
+            },
+            format!("{:.1}s", chunk.duration_ms as f64 / 1000.0),
+            chunk.tool_version.clone(),
+        ]);
+    }
+
+    let mut widths = [0; 9];
+    for row in &table {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    let mut output = String::new();
+    for row in &table {
+        let cells: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect();
+        output.push_str(cells.join("  ").trim_end());
+        output.push('\n');
+    }
+    output
+}
+
+/// Describes the totals over the history in a sentence
+fn format_history_summary(summary: &HistorySummary) -> String {
+    let mut output = format!(
+        "{} chunks committed and {} skipped in {} rooms, saving {} rows",
+        summary.committed_chunks, summary.skipped_chunks, summary.rooms, summary.rows_saved
+    );
+    if let (Some(first_ts), Some(last_ts)) = (summary.first_ts, summary.last_ts) {
+        output.push_str(&format!(
+            " (between {} and {} UTC)",
+            format_timestamp(first_ts),
+            format_timestamp(last_ts)
+        ));
+    }
+    output
 }
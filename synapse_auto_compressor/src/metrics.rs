@@ -0,0 +1,278 @@
+// This module keeps count of what the compressor has done so that it can be
+// scraped by Prometheus, either from an HTTP listener or from a file written
+// for the node exporter's textfile collector.
+
+use anyhow::{Context, Result};
+use log::{debug, warn};
+use std::{
+    fmt::Write as _,
+    fs,
+    io::{BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicI64, AtomicU64, Ordering},
+    thread,
+    time::Duration,
+};
+use synapse_compress_state::ChunkStats;
+
+/// The metrics for everything this process has compressed
+pub static METRICS: Metrics = Metrics::new();
+
+/// The phases of compressing a chunk that are timed (see `ChunkTimings`)
+const PHASES: [&str; 4] = ["load", "compress", "verify", "write"];
+
+/// How long to wait for a scrape request before giving up on it
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Counters for what the compressor has done since the process started
+pub struct Metrics {
+    chunks_processed: AtomicU64,
+    chunks_skipped: AtomicU64,
+    chunks_committed: AtomicU64,
+    /// The rows before and after compressing the chunks that were committed
+    rows_before: AtomicU64,
+    rows_after: AtomicU64,
+    /// The time spent in each of PHASES, in microseconds
+    phase_micros: [AtomicU64; 4],
+    /// -1 until a room has been picked to compress
+    lowest_uncompressed_group: AtomicI64,
+    errors: AtomicU64,
+    failed_passes: AtomicU64,
+}
+
+impl Metrics {
+    const fn new() -> Metrics {
+        Metrics {
+            chunks_processed: AtomicU64::new(0),
+            chunks_skipped: AtomicU64::new(0),
+            chunks_committed: AtomicU64::new(0),
+            rows_before: AtomicU64::new(0),
+            rows_after: AtomicU64::new(0),
+            phase_micros: [
+                AtomicU64::new(0),
+                AtomicU64::new(0),
+                AtomicU64::new(0),
+                AtomicU64::new(0),
+            ],
+            lowest_uncompressed_group: AtomicI64::new(-1),
+            errors: AtomicU64::new(0),
+            failed_passes: AtomicU64::new(0),
+        }
+    }
+
+    /// Adds a chunk that the compressor has been run on
+    pub fn record_chunk(&self, chunk_stats: &ChunkStats) {
+        self.chunks_processed.fetch_add(1, Ordering::Relaxed);
+        if chunk_stats.commited {
+            self.chunks_committed.fetch_add(1, Ordering::Relaxed);
+            self.rows_before
+                .fetch_add(chunk_stats.original_num_rows as u64, Ordering::Relaxed);
+            self.rows_after
+                .fetch_add(chunk_stats.new_num_rows as u64, Ordering::Relaxed);
+        } else {
+            self.chunks_skipped.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let timings = &chunk_stats.timings;
+        let phase_times = [
+            timings.load,
+            timings.compress,
+            timings.verify,
+            timings.write,
+        ];
+        for (total, time) in self.phase_micros.iter().zip(phase_times) {
+            total.fetch_add(time.as_micros() as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// Counts an error that stopped the compressor
+    pub fn record_error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a pass of the daemon that failed (and will be retried)
+    pub fn record_failed_pass(&self) {
+        self.failed_passes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Updates where the compressor has got to in state_compressor_total_progress
+    pub fn set_lowest_uncompressed_group(&self, group: i64) {
+        self.lowest_uncompressed_group
+            .store(group, Ordering::Relaxed);
+    }
+
+    /// Formats the metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut output = String::new();
+
+        let counters = [
+            (
+                "chunks_processed_total",
+                "Chunks that the compressor has been run on",
+                &self.chunks_processed,
+            ),
+            (
+                "chunks_skipped_total",
+                "Chunks that were skipped as no rows could be saved",
+                &self.chunks_skipped,
+            ),
+            (
+                "chunks_committed_total",
+                "Chunks whose changes were written to the database",
+                &self.chunks_committed,
+            ),
+            (
+                "rows_before_total",
+                "Rows in state_groups_state for committed chunks before they were compressed",
+                &self.rows_before,
+            ),
+            (
+                "rows_after_total",
+                "Rows in state_groups_state for committed chunks after they were compressed",
+                &self.rows_after,
+            ),
+            (
+                "errors_total",
+                "Errors that stopped a run of the compressor",
+                &self.errors,
+            ),
+            (
+                "daemon_failed_passes_total",
+                "Passes of the daemon that failed and were retried",
+                &self.failed_passes,
+            ),
+        ];
+        for (name, help, counter) in counters {
+            write_header(&mut output, name, help, "counter");
+            let _ = writeln!(
+                output,
+                "synapse_auto_compressor_{} {}",
+                name,
+                counter.load(Ordering::Relaxed)
+            );
+        }
+
+        write_header(
+            &mut output,
+            "phase_seconds_total",
+            "Time spent in each phase of compressing chunks",
+            "counter",
+        );
+        for (phase, micros) in PHASES.iter().zip(&self.phase_micros) {
+            let _ = writeln!(
+                output,
+                "synapse_auto_compressor_phase_seconds_total{{phase=\"{}\"}} {}",
+                phase,
+                micros.load(Ordering::Relaxed) as f64 / 1_000_000.0
+            );
+        }
+
+        let lowest_uncompressed_group = self.lowest_uncompressed_group.load(Ordering::Relaxed);
+        if lowest_uncompressed_group >= 0 {
+            write_header(
+                &mut output,
+                "lowest_uncompressed_group",
+                "The lowest state group that hasn't been compressed yet",
+                "gauge",
+            );
+            let _ = writeln!(
+                output,
+                "synapse_auto_compressor_lowest_uncompressed_group {}",
+                lowest_uncompressed_group
+            );
+        }
+
+        output
+    }
+}
+
+fn write_header(output: &mut String, name: &str, help: &str, metric_type: &str) {
+    let _ = writeln!(output, "# HELP synapse_auto_compressor_{} {}", name, help);
+    let _ = writeln!(
+        output,
+        "# TYPE synapse_auto_compressor_{} {}",
+        name, metric_type
+    );
+}
+
+/// Starts a thread that serves `METRICS` on `/metrics` over HTTP
+///
+/// Returns the address that is being listened on (which is useful if the
+/// port given was 0)
+///
+/// # Arguments
+///
+/// * `address`         -   The address to listen on, e.g. "127.0.0.1:9300"
+pub fn serve(address: &str) -> Result<SocketAddr> {
+    let listener = TcpListener::bind(address)
+        .with_context(|| format!("Failed to listen for metrics requests on {}", address))?;
+    let local_address = listener.local_addr()?;
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream.map_err(anyhow::Error::from).and_then(answer_request);
+            if let Err(e) = result {
+                debug!("Failed to answer a metrics request: {}", e);
+            }
+        }
+    });
+
+    Ok(local_address)
+}
+
+/// Answers a single HTTP request for the metrics
+fn answer_request(mut stream: TcpStream) -> Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+
+    // Only the request line matters, but the headers are read so that the
+    // client doesn't see the connection reset
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && header.trim_end() != "" {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", METRICS.render()),
+        _ => ("404 Not Found", "Not found\n".to_string()),
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// Writes `METRICS` to a file for the node exporter's textfile collector
+///
+/// The metrics are written to a temporary file that is then renamed over `path`,
+/// so that the collector never reads a half written file.
+pub fn write_textfile(path: &Path) -> Result<()> {
+    let mut temp_path = PathBuf::from(path);
+    temp_path.set_extension("prom.tmp");
+
+    fs::write(&temp_path, METRICS.render())
+        .with_context(|| format!("Failed to write metrics to {}", temp_path.display()))?;
+    fs::rename(&temp_path, path)
+        .with_context(|| format!("Failed to move metrics file to {}", path.display()))?;
+    Ok(())
+}
+
+/// Starts a thread that calls `write_textfile` every `interval`
+pub fn write_textfile_periodically(path: PathBuf, interval: Duration) {
+    thread::spawn(move || loop {
+        if let Err(e) = write_textfile(&path) {
+            warn!("{:?}", e);
+        }
+        thread::sleep(interval);
+    });
+}
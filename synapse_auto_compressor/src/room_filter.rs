@@ -0,0 +1,185 @@
+// This module contains the lists of rooms that the auto compressor is, and
+// isn't, allowed to compress.
+
+use anyhow::{Context, Result};
+use std::{fs, path::Path};
+
+/// Which rooms the auto compressor may compress
+///
+/// Each pattern is either a room ID (starting with `!`), which only matches that
+/// room, or a glob for the server name part of the room ID (such as `example.com`
+/// or `*.example.com`) in which `*` matches any characters and `?` matches one.
+///
+/// A room is allowed if it matches one of the `include` patterns (or there aren't
+/// any) and doesn't match any of the `exclude` patterns.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RoomFilter {
+    include: Vec<String>,
+    exclude: Vec<String>,
+}
+
+impl RoomFilter {
+    /// Creates a filter from lists of room IDs and server name globs
+    ///
+    /// Returns an error if one of the patterns is a malformed room ID
+    pub fn new(include: Vec<String>, exclude: Vec<String>) -> Result<RoomFilter, String> {
+        for pattern in include.iter().chain(&exclude) {
+            if pattern.starts_with('!') && !pattern.contains(':') {
+                return Err(format!(
+                    "'{}' is not a room ID (expected something like !abc:example.com)",
+                    pattern
+                ));
+            }
+            if pattern.is_empty() {
+                return Err("Room patterns can't be empty".to_string());
+            }
+        }
+        Ok(RoomFilter { include, exclude })
+    }
+
+    /// Whether the filter allows every room
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    /// Whether the filter allows the room to be compressed
+    pub fn allows(&self, room_id: &str) -> bool {
+        let matches = |pattern: &String| pattern_matches(pattern, room_id);
+        (self.include.is_empty() || self.include.iter().any(matches))
+            && !self.exclude.iter().any(matches)
+    }
+
+    /// Postgres regular expressions matching the room IDs that the include list
+    /// matches (to be used as `room_id ~ ANY(...)`)
+    pub fn include_regexes(&self) -> Vec<String> {
+        self.include.iter().map(|p| pattern_to_regex(p)).collect()
+    }
+
+    /// Postgres regular expressions matching the room IDs that the exclude list
+    /// matches (to be used as `room_id ~ ANY(...)`)
+    pub fn exclude_regexes(&self) -> Vec<String> {
+        self.exclude.iter().map(|p| pattern_to_regex(p)).collect()
+    }
+
+    /// A description of the filter that is the same for equivalent filters, used
+    /// to notice when the filter has changed between runs (empty if the filter
+    /// allows every room)
+    pub fn fingerprint(&self) -> String {
+        if self.is_empty() {
+            return String::new();
+        }
+
+        let sorted = |patterns: &[String]| {
+            let mut patterns = patterns.to_vec();
+            patterns.sort();
+            patterns.dedup();
+            patterns.join(" ")
+        };
+        format!(
+            "include: {}; exclude: {}",
+            sorted(&self.include),
+            sorted(&self.exclude)
+        )
+    }
+}
+
+/// Reads a list of room IDs and server name globs from a file, one per line
+///
+/// Blank lines, and anything after a `#`, are ignored.
+pub fn read_room_list(path: &Path) -> Result<Vec<String>> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read room list from {}", path.display()))?;
+
+    Ok(contents
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or("").trim())
+        .filter(|line| !line.is_empty())
+        .map(String::from)
+        .collect())
+}
+
+/// Whether a room ID matches a room ID or server name glob
+fn pattern_matches(pattern: &str, room_id: &str) -> bool {
+    if pattern.starts_with('!') {
+        return pattern == room_id;
+    }
+
+    match room_id.split_once(':') {
+        Some((_, server_name)) => {
+            let pattern: Vec<char> = pattern.chars().collect();
+            let server_name: Vec<char> = server_name.chars().collect();
+            glob_matches(&pattern, &server_name)
+        }
+        None => false,
+    }
+}
+
+/// Matches a glob where `*` matches any characters and `?` matches one
+///
+/// This works on characters rather than bytes, so that `?` matches a non-ASCII
+/// character in the same way as the `.` it becomes in `pattern_to_regex`.
+///
+/// This only ever backtracks to the last `*`, so it takes at most
+/// `pattern.len() * text.len()` steps however many `*`s there are.
+fn glob_matches(pattern: &[char], text: &[char]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // The position in the pattern just after the last `*`, and the position
+    // in the text that it has been matched up to
+    let mut last_star: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                p += 1;
+                last_star = Some((p, t));
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            // Let the last `*` match one more character and try again
+            _ => match last_star {
+                Some((star_p, star_t)) => {
+                    p = star_p;
+                    t = star_t + 1;
+                    last_star = Some((star_p, t));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Converts a room ID or server name glob into a Postgres regular expression that
+/// matches the same room IDs
+fn pattern_to_regex(pattern: &str) -> String {
+    let mut regex = String::from("^");
+    if pattern.starts_with('!') {
+        regex.push_str(&escape_regex(pattern));
+    } else {
+        // The server name is everything after the first colon
+        regex.push_str("[^:]*:");
+        for c in pattern.chars() {
+            match c {
+                '*' => regex.push_str(".*"),
+                '?' => regex.push('.'),
+                c => regex.push_str(&escape_regex(&c.to_string())),
+            }
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+fn escape_regex(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if "\\.^$*+?()[]{}|".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
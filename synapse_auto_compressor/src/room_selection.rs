@@ -0,0 +1,279 @@
+// This module contains the strategies that the auto compressor can use to
+// decide which room to compress a chunk of next.
+
+use crate::{room_filter::RoomFilter, state_saving::get_next_room_to_compress};
+use anyhow::Result;
+use log::trace;
+use postgres::Client;
+use std::str::FromStr;
+
+/// The names of the built in room selection strategies, as used on the command line
+pub const ROOM_SELECTIONS: &[&str] = &[
+    "lowest-id",
+    "largest",
+    "most-uncompressed",
+    "estimated-savings",
+];
+
+/// Decides which room the auto compressor should compress a chunk of next
+///
+/// Implement this to use a strategy other than the built in ones (see
+/// `RoomSelection`). Strategies that can cheaply pick a room implement
+/// `next_room`, and ones that have to look at every room to decide implement
+/// `rank_rooms` instead. The selector is shared between the workers, which
+/// never call it at the same time. Selectors must only return rooms that
+/// the room filter allows (see `RoomFilter::allows`, or the regexes it
+/// provides for use in queries).
+pub trait RoomSelector: Sync {
+    /// Returns the rooms with uncompressed state groups, best first, or None
+    /// if this selector doesn't rank rooms (the default)
+    ///
+    /// The workers rank the rooms at the start of a run and again every so
+    /// many chunks (see `ranks_once_per_run`), without holding the lock they
+    /// share, and then pick the first ranked room that isn't in progress. This
+    /// means it can be as slow as it needs to be.
+    ///
+    /// # Arguments
+    ///
+    /// * `client`              -   A postgres client used to send the requests to the database
+    /// * `room_filter`         -   Which rooms may be compressed
+    fn rank_rooms(
+        &self,
+        _client: &mut Client,
+        _room_filter: &RoomFilter,
+    ) -> Result<Option<Vec<String>>> {
+        Ok(None)
+    }
+
+    /// Whether ranking the rooms is too slow to repeat during a run
+    ///
+    /// By default the rooms are ranked again every so many chunks. If this
+    /// returns true then they are ranked once at the start of each run (so
+    /// once per pass of the daemon), and only ranked again once every ranked
+    /// room is in progress or finished.
+    fn ranks_once_per_run(&self) -> bool {
+        false
+    }
+
+    /// Returns the room to compress a chunk of next, or None if there are no
+    /// rooms with uncompressed state groups left
+    ///
+    /// This is called for every chunk if `rank_rooms` returns None, while the
+    /// workers' shared lock is held. By default it ranks the rooms and returns
+    /// the first one that isn't in progress.
+    ///
+    /// # Arguments
+    ///
+    /// * `client`              -   A postgres client used to send the requests to the database
+    /// * `rooms_in_progress`   -   Rooms that are being compressed by other workers (or that
+    ///                             another compressor is working on), which mustn't be returned
+    /// * `room_filter`         -   Which rooms may be compressed
+    fn next_room(
+        &self,
+        client: &mut Client,
+        rooms_in_progress: &[String],
+        room_filter: &RoomFilter,
+    ) -> Result<Option<String>> {
+        let ranked_rooms = self.rank_rooms(client, room_filter)?.unwrap_or_default();
+        Ok(first_not_in_progress(&ranked_rooms, rooms_in_progress))
+    }
+}
+
+/// Returns the first of `ranked_rooms` that isn't in `rooms_in_progress`
+pub(crate) fn first_not_in_progress(
+    ranked_rooms: &[String],
+    rooms_in_progress: &[String],
+) -> Option<String> {
+    ranked_rooms
+        .iter()
+        .find(|room| !rooms_in_progress.contains(room))
+        .cloned()
+}
+
+/// The built in room selection strategies
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoomSelection {
+    /// The room owning the lowest uncompressed state group. This works through
+    /// the database in the order the rooms were created.
+    LowestId,
+    /// The room with the most rows in state_groups_state
+    Largest,
+    /// The room with the most state groups that haven't been compressed yet
+    MostUncompressed,
+    /// The room that is estimated to save the most rows (see `EstimatedSavings`)
+    EstimatedSavings,
+}
+
+impl FromStr for RoomSelection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lowest-id" => Ok(RoomSelection::LowestId),
+            "largest" => Ok(RoomSelection::Largest),
+            "most-uncompressed" => Ok(RoomSelection::MostUncompressed),
+            "estimated-savings" => Ok(RoomSelection::EstimatedSavings),
+            _ => Err(format!(
+                "'{}' is not a valid room selection (expected one of: {})",
+                s,
+                ROOM_SELECTIONS.join(", ")
+            )),
+        }
+    }
+}
+
+impl RoomSelection {
+    /// The selector that implements this strategy
+    pub fn selector(self) -> &'static dyn RoomSelector {
+        match self {
+            RoomSelection::LowestId => &LowestId,
+            RoomSelection::Largest => &Largest,
+            RoomSelection::MostUncompressed => &MostUncompressed,
+            RoomSelection::EstimatedSavings => &EstimatedSavings,
+        }
+    }
+}
+
+/// Picks the room owning the lowest uncompressed state group (see
+/// `get_next_room_to_compress`)
+pub struct LowestId;
+
+impl RoomSelector for LowestId {
+    fn next_room(
+        &self,
+        client: &mut Client,
+        rooms_in_progress: &[String],
+        room_filter: &RoomFilter,
+    ) -> Result<Option<String>> {
+        get_next_room_to_compress(client, rooms_in_progress, room_filter)
+    }
+}
+
+/// Picks the room with the most rows in state_groups_state that still has
+/// uncompressed state groups
+///
+/// Note: this counts every row in state_groups_state each time the rooms are
+/// ranked, which can take a while on large databases, so the rooms are only
+/// ranked once per run.
+pub struct Largest;
+
+impl RoomSelector for Largest {
+    fn rank_rooms(
+        &self,
+        client: &mut Client,
+        room_filter: &RoomFilter,
+    ) -> Result<Option<Vec<String>>> {
+        let get_ranked_rooms = r#"
+            SELECT room_id
+            FROM (
+                SELECT room_id, COUNT(*) AS num_rows
+                FROM state_groups_state
+                GROUP BY room_id
+            ) AS room_sizes
+            WHERE
+                (cardinality($1::TEXT[]) = 0 OR room_id ~ ANY($1))
+                AND NOT (room_id ~ ANY($2))
+                AND EXISTS (
+                    SELECT 1
+                    FROM state_groups
+                    LEFT JOIN state_compressor_progress USING (room_id)
+                    WHERE
+                        state_groups.room_id = room_sizes.room_id
+                        AND (id > last_compressed OR last_compressed IS NULL)
+                )
+            ORDER BY num_rows DESC, room_id ASC
+        "#;
+
+        query_ranked_rooms(client, get_ranked_rooms, room_filter).map(Some)
+    }
+
+    fn ranks_once_per_run(&self) -> bool {
+        true
+    }
+}
+
+/// Picks the room with the most state groups after its `last_compressed` group
+pub struct MostUncompressed;
+
+impl RoomSelector for MostUncompressed {
+    fn rank_rooms(
+        &self,
+        client: &mut Client,
+        room_filter: &RoomFilter,
+    ) -> Result<Option<Vec<String>>> {
+        let get_ranked_rooms = r#"
+            SELECT room_id
+            FROM state_groups
+            LEFT JOIN state_compressor_progress USING (room_id)
+            WHERE
+                (id > last_compressed OR last_compressed IS NULL)
+                AND (cardinality($1::TEXT[]) = 0 OR room_id ~ ANY($1))
+                AND NOT (room_id ~ ANY($2))
+            GROUP BY room_id
+            ORDER BY COUNT(*) DESC, room_id ASC
+        "#;
+
+        query_ranked_rooms(client, get_ranked_rooms, room_filter).map(Some)
+    }
+}
+
+/// Picks the room that is estimated to save the most rows
+///
+/// The compressor can at best store each state group as a delta of a single row,
+/// so the savings for a room are estimated as the number of rows belonging to its
+/// uncompressed state groups minus the number of those groups. This is only rough,
+/// as it doesn't know how much state the groups share.
+///
+/// Like `Largest`, this counts the rows of every uncompressed state group, so the
+/// rooms are only ranked once per run.
+pub struct EstimatedSavings;
+
+impl RoomSelector for EstimatedSavings {
+    fn rank_rooms(
+        &self,
+        client: &mut Client,
+        room_filter: &RoomFilter,
+    ) -> Result<Option<Vec<String>>> {
+        let get_ranked_rooms = r#"
+            SELECT g.room_id
+            FROM state_groups AS g
+            LEFT JOIN state_compressor_progress AS p USING (room_id)
+            LEFT JOIN state_groups_state AS s ON (s.state_group = g.id)
+            WHERE
+                (g.id > p.last_compressed OR p.last_compressed IS NULL)
+                AND (cardinality($1::TEXT[]) = 0 OR g.room_id ~ ANY($1))
+                AND NOT (g.room_id ~ ANY($2))
+            GROUP BY g.room_id
+            ORDER BY COUNT(s.state_group) - COUNT(DISTINCT g.id) DESC, g.room_id ASC
+        "#;
+
+        query_ranked_rooms(client, get_ranked_rooms, room_filter).map(Some)
+    }
+
+    fn ranks_once_per_run(&self) -> bool {
+        true
+    }
+}
+
+/// Runs a query that returns room_ids in order, with the room filter's include
+/// and exclude regexes as its parameters
+fn query_ranked_rooms(
+    client: &mut Client,
+    query: &str,
+    room_filter: &RoomFilter,
+) -> Result<Vec<String>> {
+    let include_rooms = room_filter.include_regexes();
+    let exclude_rooms = room_filter.exclude_regexes();
+    let ranked_rooms: Vec<String> = client
+        .query(query, &[&include_rooms, &exclude_rooms])?
+        .iter()
+        .map(|row| row.get("room_id"))
+        .collect();
+
+    trace!(
+        "ranked {} rooms: {:?}",
+        ranked_rooms.len(),
+        ranked_rooms.first()
+    );
+    Ok(ranked_rooms)
+}